@@ -0,0 +1,135 @@
+//! Overhead of the field-level instrumentation, per resolved field.
+//!
+//! The same operation is executed without the extension, with the extension but without
+//! instrumentation, and with every field instrumented, on wide and on deep responses.
+use async_graphql::{
+    ComplexObject, EmptyMutation, EmptySubscription, Object, Request, Schema, SimpleObject,
+};
+use async_graphql_extension_apollo_tracing::{
+    ApolloTracing, ApolloTracingConfig, DeliveryError, Report, ReportSink, TraceSampler,
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::Runtime;
+
+/// Reports are dropped, only the extension is measured.
+struct NoopSink;
+
+#[async_trait::async_trait]
+impl ReportSink for NoopSink {
+    async fn send(&self, _report: &Report) -> Result<(), DeliveryError> {
+        Ok(())
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+struct Item {
+    id: i32,
+    name: String,
+}
+
+#[ComplexObject]
+impl Item {
+    async fn child(&self) -> Option<Item> {
+        (self.id > 0).then(|| item(self.id - 1))
+    }
+}
+
+fn item(id: i32) -> Item {
+    Item {
+        id,
+        name: format!("item {id}"),
+    }
+}
+
+struct Query;
+
+#[Object]
+impl Query {
+    async fn items(&self, count: i32) -> Vec<Item> {
+        (0..count).map(|_| item(0)).collect()
+    }
+
+    async fn deep(&self, depth: i32) -> Item {
+        item(depth)
+    }
+}
+
+type BenchSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+fn schema(tracing: Option<ApolloTracing>) -> BenchSchema {
+    let builder = Schema::build(Query, EmptyMutation, EmptySubscription);
+    match tracing {
+        Some(tracing) => builder.extension(tracing),
+        None => builder,
+    }
+    .finish()
+}
+
+fn tracing(ratio: f64) -> ApolloTracing {
+    let config = ApolloTracingConfig::builder()
+        .authorization_token("token")
+        .graph_ref("bench@current")
+        .sink(NoopSink)
+        .build()
+        .unwrap();
+    ApolloTracing::from_config(config).with_sampler(TraceSampler::ratio(ratio))
+}
+
+/// The query and the number of fields it resolves.
+fn wide(count: u64) -> (String, u64) {
+    // `items`, then each item, its `id` and its `name`.
+    (
+        format!("{{ items(count: {count}) {{ id name }} }}"),
+        1 + count * 3,
+    )
+}
+
+fn deep(depth: u64) -> (String, u64) {
+    let mut selection = "id name".to_string();
+    for _ in 0..depth {
+        selection = format!("id name child {{ {selection} }}");
+    }
+    // `deep`, then `id` and `name` at each level, and the `child` of the levels but the last.
+    (
+        format!("{{ deep(depth: {depth}) {{ {selection} }} }}"),
+        1 + (depth + 1) * 2 + depth,
+    )
+}
+
+fn bench_instrumentation(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    // The aggregator of the extension is spawned on the runtime.
+    let _guard = runtime.enter();
+    let schemas = [
+        ("no extension", schema(None)),
+        ("not sampled", schema(Some(tracing(0.)))),
+        ("sampled", schema(Some(tracing(1.)))),
+    ];
+
+    let mut group = c.benchmark_group("resolve");
+    let queries = [
+        ("wide", wide(10)),
+        ("wide", wide(100)),
+        ("wide", wide(1000)),
+        ("deep", deep(8)),
+        ("deep", deep(24)),
+    ];
+    for (shape, (query, fields)) in queries {
+        group.throughput(Throughput::Elements(fields));
+        for (name, schema) in &schemas {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{shape}/{name}"), fields),
+                &query,
+                |b, query| {
+                    b.to_async(&runtime)
+                        .iter(|| schema.execute(Request::new(query.as_str())))
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_instrumentation);
+criterion_main!(benches);
@@ -79,7 +79,6 @@ where
                         .client_name("Sample_Client")
                         .client_version("v4")
                         .method(Method::Post)
-                        .status_code(200u32)
                         .build()
                         .unwrap(),
                 );
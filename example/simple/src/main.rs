@@ -1,6 +1,6 @@
 use async_graphql::{http::GraphiQLSource, EmptyMutation, EmptySubscription, Schema};
 use async_graphql_extension_apollo_tracing::{
-    register::register, ApolloTracing, ApolloTracingDataExt,
+    register::register, ApolloTracing, ApolloTracingConfig, ApolloTracingDataExt,
 };
 use axum::{
     response::{self, IntoResponse},
@@ -32,12 +32,14 @@ async fn main() {
 
     let schema = Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
         .data(StarWars::new())
-        .extension(ApolloTracing::new(
-            "AUTH_KEY".into(),
-            "mac-local".into(),
-            "testblbl".into(),
-            "new".into(),
-            "v1.0.0".into(),
+        .extension(ApolloTracing::from_config(
+            ApolloTracingConfig::builder()
+                .authorization_token("AUTH_KEY")
+                .graph_ref("testblbl@new")
+                .hostname("mac-local")
+                .service_version("v1.0.0")
+                .build()
+                .expect("invalid Apollo Studio configuration"),
         ))
         .finish();
 
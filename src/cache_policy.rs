@@ -0,0 +1,255 @@
+//! # Cache policies
+//!
+//! Conversion of the async_graphql [CacheControl], computed from the `cache_control` hints of the
+//! schema, into the [CachePolicy] of the traces.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_graphql::registry::{MetaType, Registry};
+use async_graphql::{CacheControl, SchemaEnv};
+use protobuf::{EnumOrUnknown, MessageField};
+
+use crate::proto::reports::trace::{cache_policy::Scope, CachePolicy};
+
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+
+fn cache_policy(cache_control: &CacheControl, max_age_ns: i64) -> MessageField<CachePolicy> {
+    let scope = if cache_control.public {
+        Scope::PUBLIC
+    } else {
+        Scope::PRIVATE
+    };
+    MessageField::some(CachePolicy {
+        scope: EnumOrUnknown::new(scope),
+        max_age_ns,
+        special_fields: Default::default(),
+    })
+}
+
+/// Cache policy of a whole response, only set when the response can be cached.
+pub fn response_cache_policy(cache_control: &CacheControl) -> MessageField<CachePolicy> {
+    if cache_control.max_age > 0 {
+        cache_policy(
+            cache_control,
+            i64::from(cache_control.max_age) * NANOS_PER_SECOND,
+        )
+    } else {
+        MessageField::none()
+    }
+}
+
+/// Cache policies of the fields of a schema with a cache hint, computed once instead of looking
+/// the hints up in the registry for every resolved field.
+#[derive(Debug, Default)]
+pub struct FieldCachePolicies(HashMap<String, HashMap<String, CachePolicy>>);
+
+impl FieldCachePolicies {
+    pub fn new(registry: &Registry) -> Self {
+        let mut policies: HashMap<String, HashMap<String, CachePolicy>> = HashMap::new();
+        for (type_name, ty) in &registry.types {
+            for (field_name, field) in ty.fields().into_iter().flatten() {
+                let policy = field_cache_policy(registry, type_name, field_name, &field.ty);
+                if let Some(policy) = policy.into_option() {
+                    policies
+                        .entry(type_name.clone())
+                        .or_default()
+                        .insert(field_name.clone(), policy);
+                }
+            }
+        }
+        Self(policies)
+    }
+
+    /// Cache policy of the field, not set when it has no hint.
+    pub fn get(&self, parent_type: &str, field_name: &str) -> MessageField<CachePolicy> {
+        if self.0.is_empty() {
+            return MessageField::none();
+        }
+        self.0
+            .get(parent_type)
+            .and_then(|fields| fields.get(field_name))
+            .cloned()
+            .into()
+    }
+}
+
+/// [FieldCachePolicies] of every schema the extension is registered in. The environment of each
+/// schema is kept so its registry can't be mistaken for the one of another schema.
+#[derive(Default)]
+pub struct SchemaCachePolicies(Mutex<Vec<(SchemaEnv, Arc<FieldCachePolicies>)>>);
+
+impl SchemaCachePolicies {
+    /// Policies of the fields of the schema, computed by its first request.
+    pub fn get(&self, schema_env: &SchemaEnv) -> Arc<FieldCachePolicies> {
+        let mut schemas = self.0.lock().unwrap();
+        let known = schemas
+            .iter()
+            .find(|(env, _)| std::ptr::eq(&env.registry, &schema_env.registry));
+        if let Some((_, policies)) = known {
+            return policies.clone();
+        }
+        let policies = Arc::new(FieldCachePolicies::new(&schema_env.registry));
+        schemas.push((schema_env.clone(), policies.clone()));
+        policies
+    }
+}
+
+/// Cache policy of a field, from the hints on the field and on the type it returns. Not set when
+/// there is no hint.
+fn field_cache_policy(
+    registry: &Registry,
+    parent_type: &str,
+    field_name: &str,
+    return_type: &str,
+) -> MessageField<CachePolicy> {
+    let field_hint = registry
+        .types
+        .get(parent_type)
+        .and_then(|ty| ty.field_by_name(field_name))
+        .map(|field| field.cache_control);
+    let type_hint = match registry.concrete_type_by_name(return_type) {
+        Some(MetaType::Object { cache_control, .. }) => Some(*cache_control),
+        _ => None,
+    };
+
+    let cache_control = match (field_hint, type_hint) {
+        (Some(field), Some(ty)) => merge(field, ty),
+        (Some(hint), None) | (None, Some(hint)) => hint,
+        (None, None) => return MessageField::none(),
+    };
+    if cache_control == CacheControl::default() {
+        return MessageField::none();
+    }
+
+    // The protobuf uses 0 for an absent max age and -1 for a max age of 0, which async_graphql
+    // represents with 0 and -1 too.
+    let max_age_ns = match cache_control.max_age {
+        max_age if max_age > 0 => i64::from(max_age) * NANOS_PER_SECOND,
+        max_age => i64::from(max_age),
+    };
+    cache_policy(&cache_control, max_age_ns)
+}
+
+/// Merge two hints like async_graphql does: the most restrictive one wins.
+fn merge(a: CacheControl, b: CacheControl) -> CacheControl {
+    CacheControl {
+        public: a.public && b.public,
+        max_age: match (a.max_age, b.max_age) {
+            (-1, _) | (_, -1) => -1,
+            (a, 0) => a,
+            (0, b) => b,
+            (a, b) => a.min(b),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{Object, OutputType, SimpleObject};
+
+    use super::*;
+
+    #[derive(SimpleObject)]
+    #[graphql(cache_control(max_age = 60))]
+    struct Product {
+        id: i32,
+        #[graphql(cache_control(max_age = 10, private))]
+        price: i32,
+    }
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        #[graphql(cache_control(max_age = 30))]
+        async fn product(&self) -> Product {
+            Product { id: 1, price: 1 }
+        }
+
+        async fn products(&self) -> Vec<Product> {
+            Vec::new()
+        }
+
+        #[graphql(cache_control(no_cache))]
+        async fn live(&self) -> i32 {
+            1
+        }
+
+        async fn value(&self) -> i32 {
+            1
+        }
+    }
+
+    fn policies() -> FieldCachePolicies {
+        let mut registry = Registry::default();
+        Query::create_type_info(&mut registry);
+        FieldCachePolicies::new(&registry)
+    }
+
+    /// Scope and max age of a cache policy, `None` when it isn't set.
+    fn hint(policy: MessageField<CachePolicy>) -> Option<(Scope, i64)> {
+        policy
+            .into_option()
+            .map(|policy| (policy.scope.enum_value().unwrap(), policy.max_age_ns))
+    }
+
+    #[test]
+    fn keep_the_most_restrictive_hint_of_fields_and_their_types() {
+        let policies = policies();
+        assert_eq!(
+            hint(policies.get("Query", "product")),
+            Some((Scope::PUBLIC, 30 * NANOS_PER_SECOND))
+        );
+        assert_eq!(
+            hint(policies.get("Query", "products")),
+            Some((Scope::PUBLIC, 60 * NANOS_PER_SECOND))
+        );
+        assert_eq!(
+            hint(policies.get("Product", "price")),
+            Some((Scope::PRIVATE, 10 * NANOS_PER_SECOND))
+        );
+    }
+
+    #[test]
+    fn send_no_cache_as_a_max_age_of_minus_one() {
+        assert_eq!(
+            hint(policies().get("Query", "live")),
+            Some((Scope::PUBLIC, -1))
+        );
+    }
+
+    #[test]
+    fn skip_fields_without_hint() {
+        let policies = policies();
+        assert_eq!(hint(policies.get("Query", "value")), None);
+        assert_eq!(hint(policies.get("Product", "id")), None);
+        assert_eq!(hint(policies.get("Unknown", "field")), None);
+    }
+
+    #[test]
+    fn only_send_the_policy_of_cacheable_responses() {
+        let cacheable = CacheControl {
+            public: false,
+            max_age: 20,
+        };
+        assert_eq!(
+            hint(response_cache_policy(&cacheable)),
+            Some((Scope::PRIVATE, 20 * NANOS_PER_SECOND))
+        );
+        assert_eq!(hint(response_cache_policy(&CacheControl::default())), None);
+        let no_cache = CacheControl {
+            public: true,
+            max_age: -1,
+        };
+        assert_eq!(hint(response_cache_policy(&no_cache)), None);
+    }
+
+    #[test]
+    fn merge_hints_like_async_graphql() {
+        let hint = |public, max_age| CacheControl { public, max_age };
+        assert_eq!(merge(hint(true, 30), hint(true, 60)), hint(true, 30));
+        assert_eq!(merge(hint(true, 0), hint(false, 60)), hint(false, 60));
+        assert_eq!(merge(hint(true, 30), hint(true, 0)), hint(true, 30));
+        assert_eq!(merge(hint(true, -1), hint(true, 60)), hint(true, -1));
+    }
+}
@@ -0,0 +1,406 @@
+//! # Configuration
+//!
+//! Everything needed to connect the extension to Apollo Studio, built with
+//! [ApolloTracingConfigBuilder] which validates the values before the aggregator starts.
+use std::sync::Arc;
+use std::time::Duration;
+
+use derive_builder::UninitializedFieldError;
+
+use crate::{CircuitBreakerPolicy, ReportSink, RetryPolicy, SpoolPolicy, DEFAULT_REPORTING_URL};
+
+/// Configuration of the [ApolloTracing](crate::ApolloTracing) extension.
+///
+/// Only the authorization token and the graph ref are required, use
+/// [ApolloTracingConfig::builder] to set them and
+/// [ApolloTracing::from_config](crate::ApolloTracing::from_config) to start the extension.
+#[derive(Clone, derive_builder::Builder)]
+#[builder(
+    pattern = "owned",
+    setter(into, strip_option),
+    build_fn(
+        private,
+        name = "build_unvalidated",
+        error = "ApolloTracingConfigError"
+    )
+)]
+pub struct ApolloTracingConfig {
+    /// The API key used to send reports to Apollo Studio.
+    pub(crate) authorization_token: String,
+    /// The graph the reports are sent to, as `graph-id@variant`. The variant defaults to
+    /// `current` when omitted.
+    pub(crate) graph_ref: String,
+    /// Hostname of the server, like `yourdomain-graphql-1.io`.
+    #[builder(default)]
+    pub(crate) hostname: String,
+    /// Your release version or release name from Git for example.
+    #[builder(default)]
+    pub(crate) service_version: String,
+    /// Overrides the `uname` reported, detected from the system by default.
+    #[builder(default)]
+    pub(crate) uname: Option<String>,
+    /// Overrides the agent version reported, the name and version of this crate by default.
+    #[builder(default)]
+    pub(crate) agent_version: Option<String>,
+    /// Overrides the runtime version reported, `Rust` by default.
+    #[builder(default)]
+    pub(crate) runtime_version: Option<String>,
+    /// Overrides the executable schema id reported, the graph id by default.
+    #[builder(default)]
+    pub(crate) executable_schema_id: Option<String>,
+    /// A report is sent as soon as this number of traces is aggregated. Defaults to 64.
+    #[builder(default = "64")]
+    pub(crate) max_traces: usize,
+    /// A report is sent at least this often when traces are pending, whatever the traffic.
+    /// Defaults to 5 seconds.
+    #[builder(default = "Duration::from_secs(5)")]
+    pub(crate) flush_interval: Duration,
+    /// Number of traces waiting for the aggregator, the next ones are dropped while it's full.
+    /// Defaults to 32.
+    #[builder(default = "32")]
+    pub(crate) channel_capacity: usize,
+    /// How long a single attempt to send a report can take before being considered failed and
+    /// retried. Each endpoint gets this long, the next one is tried when it runs out. Defaults to
+    /// 30 seconds.
+    #[builder(default = "Duration::from_secs(30)")]
+    pub(crate) send_timeout: Duration,
+    /// How reports failing to be sent are retried.
+    #[builder(default)]
+    pub(crate) retry: RetryPolicy,
+    /// When to stop sending reports after repeated fatal failures.
+    #[builder(default)]
+    pub(crate) circuit_breaker: CircuitBreakerPolicy,
+    /// Where reports which can't be sent are kept to be sent later, see [SpoolPolicy]. They're
+    /// dropped by default.
+    #[builder(default)]
+    pub(crate) spool: Option<SpoolPolicy>,
+    /// Endpoints the reports are sent to, by order of preference. The next one is used when
+    /// the previous one can't be reached. Defaults to [DEFAULT_REPORTING_URL].
+    #[builder(setter(custom), default = "vec![DEFAULT_REPORTING_URL.to_string()]")]
+    pub(crate) endpoints: Vec<String>,
+    /// Where the reports are sent, an [ApolloHttpSink](crate::ApolloHttpSink) using the
+    /// authorization token and the endpoints by default.
+    #[builder(setter(custom), default)]
+    pub(crate) sink: Option<Arc<dyn ReportSink>>,
+}
+
+impl std::fmt::Debug for ApolloTracingConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApolloTracingConfig")
+            .field("graph_ref", &self.graph_ref)
+            .field("hostname", &self.hostname)
+            .field("service_version", &self.service_version)
+            .field("uname", &self.uname)
+            .field("agent_version", &self.agent_version)
+            .field("runtime_version", &self.runtime_version)
+            .field("executable_schema_id", &self.executable_schema_id)
+            .field("max_traces", &self.max_traces)
+            .field("flush_interval", &self.flush_interval)
+            .field("channel_capacity", &self.channel_capacity)
+            .field("send_timeout", &self.send_timeout)
+            .field("retry", &self.retry)
+            .field("circuit_breaker", &self.circuit_breaker)
+            .field("spool", &self.spool)
+            .field("endpoints", &self.endpoints)
+            .field("sink", &self.sink.as_ref().map(|_| "custom"))
+            .finish_non_exhaustive()
+    }
+}
+
+impl ApolloTracingConfig {
+    pub fn builder() -> ApolloTracingConfigBuilder {
+        ApolloTracingConfigBuilder::default()
+    }
+
+    /// The graph id and the variant of the graph ref.
+    pub(crate) fn graph_id_and_variant(&self) -> (&str, &str) {
+        split_graph_ref(&self.graph_ref)
+    }
+}
+
+impl ApolloTracingConfigBuilder {
+    /// Send the reports to these endpoints, by order of preference. Useful to go through an
+    /// egress proxy, to use a compatible collector or a local stand-in in tests.
+    pub fn endpoints<I, E>(mut self, endpoints: I) -> Self
+    where
+        I: IntoIterator<Item = E>,
+        E: Into<String>,
+    {
+        self.endpoints = Some(endpoints.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Send the reports to `sink` instead of Apollo Studio, see [ReportSink].
+    pub fn sink(mut self, sink: impl ReportSink + 'static) -> Self {
+        self.sink = Some(Some(Arc::new(sink)));
+        self
+    }
+
+    /// Validate the configuration and build it.
+    pub fn build(self) -> Result<ApolloTracingConfig, ApolloTracingConfigError> {
+        self.validate()?;
+        self.build_unvalidated()
+    }
+
+    /// Build without validating, for [ApolloTracing::new](crate::ApolloTracing::new) which
+    /// always accepted any value.
+    pub(crate) fn build_unchecked(self) -> ApolloTracingConfig {
+        self.build_unvalidated()
+            .expect("the authorization token and the graph ref are set")
+    }
+
+    fn validate(&self) -> Result<(), ApolloTracingConfigError> {
+        if let Some(graph_ref) = &self.graph_ref {
+            validate_graph_ref(graph_ref)?;
+        }
+        if matches!(&self.authorization_token, Some(token) if token.is_empty()) {
+            return Err(ApolloTracingConfigError::InvalidValue {
+                field: "authorization_token",
+                reason: "must not be empty".to_string(),
+            });
+        }
+        let zero = |field| ApolloTracingConfigError::InvalidValue {
+            field,
+            reason: "must be greater than zero".to_string(),
+        };
+        if self.max_traces == Some(0) {
+            return Err(zero("max_traces"));
+        }
+        if self.channel_capacity == Some(0) {
+            return Err(zero("channel_capacity"));
+        }
+        if self.flush_interval == Some(Duration::ZERO) {
+            return Err(zero("flush_interval"));
+        }
+        if self.send_timeout == Some(Duration::ZERO) {
+            return Err(zero("send_timeout"));
+        }
+        if matches!(&self.endpoints, Some(endpoints) if endpoints.is_empty()) {
+            return Err(ApolloTracingConfigError::InvalidValue {
+                field: "endpoints",
+                reason: "at least one endpoint is required".to_string(),
+            });
+        }
+        if let Some(Some(spool)) = &self.spool {
+            if spool.max_file_size == 0 || spool.max_file_size > spool.max_size {
+                return Err(ApolloTracingConfigError::InvalidValue {
+                    field: "spool",
+                    reason: "the maximum file size must be between 1 and the maximum size"
+                        .to_string(),
+                });
+            }
+            if spool.replay_interval.is_zero() || spool.max_age.is_zero() {
+                return Err(ApolloTracingConfigError::InvalidValue {
+                    field: "spool",
+                    reason: "the replay interval and the maximum age must be greater than zero"
+                        .to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why an [ApolloTracingConfig] couldn't be built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApolloTracingConfigError {
+    /// A required field wasn't set.
+    MissingField(&'static str),
+    /// The graph ref isn't formatted as `graph-id@variant`.
+    InvalidGraphRef { graph_ref: String, reason: String },
+    /// A field has a value which can't be used.
+    InvalidValue { field: &'static str, reason: String },
+}
+
+impl std::fmt::Display for ApolloTracingConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingField(field) => write!(f, "`{field}` must be set"),
+            Self::InvalidGraphRef { graph_ref, reason } => {
+                write!(f, "invalid graph ref `{graph_ref}`: {reason}")
+            }
+            Self::InvalidValue { field, reason } => write!(f, "invalid `{field}`: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ApolloTracingConfigError {}
+
+impl From<UninitializedFieldError> for ApolloTracingConfigError {
+    fn from(err: UninitializedFieldError) -> Self {
+        Self::MissingField(err.field_name())
+    }
+}
+
+fn split_graph_ref(graph_ref: &str) -> (&str, &str) {
+    graph_ref.split_once('@').unwrap_or((graph_ref, "current"))
+}
+
+fn validate_graph_ref(graph_ref: &str) -> Result<(), ApolloTracingConfigError> {
+    let invalid = |reason: &str| ApolloTracingConfigError::InvalidGraphRef {
+        graph_ref: graph_ref.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let (graph_id, variant) = split_graph_ref(graph_ref);
+    if graph_id.is_empty() {
+        return Err(invalid("the graph id is empty"));
+    }
+    if !graph_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(invalid(
+            "the graph id can only contain letters, digits, `-` and `_`",
+        ));
+    }
+    if variant.is_empty() {
+        return Err(invalid("the variant is empty"));
+    }
+    if variant.chars().any(|c| c == '@' || c.is_whitespace()) {
+        return Err(invalid("the variant can't contain `@` or whitespaces"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> ApolloTracingConfigBuilder {
+        ApolloTracingConfig::builder()
+            .authorization_token("token")
+            .graph_ref("graph@current")
+    }
+
+    /// Field rejected when building the configuration.
+    fn rejected(builder: ApolloTracingConfigBuilder) -> &'static str {
+        match builder.build() {
+            Err(ApolloTracingConfigError::InvalidValue { field, .. }) => field,
+            Err(ApolloTracingConfigError::InvalidGraphRef { .. }) => "graph_ref",
+            Err(ApolloTracingConfigError::MissingField(field)) => field,
+            Ok(config) => panic!("{config:?} was accepted"),
+        }
+    }
+
+    #[test]
+    fn default_values() {
+        let config = builder().build().unwrap();
+        assert_eq!(config.graph_id_and_variant(), ("graph", "current"));
+        assert_eq!(config.hostname, "");
+        assert_eq!(config.service_version, "");
+        assert_eq!(config.uname, None);
+        assert_eq!(config.agent_version, None);
+        assert_eq!(config.runtime_version, None);
+        assert_eq!(config.executable_schema_id, None);
+        assert_eq!(config.max_traces, 64);
+        assert_eq!(config.flush_interval, Duration::from_secs(5));
+        assert_eq!(config.channel_capacity, 32);
+        assert_eq!(config.send_timeout, Duration::from_secs(30));
+        assert_eq!(config.retry.max_retries, 4);
+        assert_eq!(config.circuit_breaker.failure_threshold, 3);
+        assert!(config.spool.is_none());
+        assert_eq!(config.endpoints, vec![DEFAULT_REPORTING_URL]);
+        assert!(config.sink.is_none());
+    }
+
+    #[test]
+    fn default_variant() {
+        let config = builder().graph_ref("graph").build().unwrap();
+        assert_eq!(config.graph_id_and_variant(), ("graph", "current"));
+    }
+
+    #[test]
+    fn reject_missing_fields() {
+        let builder = ApolloTracingConfig::builder().graph_ref("graph@current");
+        assert_eq!(rejected(builder), "authorization_token");
+        let builder = ApolloTracingConfig::builder().authorization_token("token");
+        assert_eq!(rejected(builder), "graph_ref");
+    }
+
+    #[test]
+    fn reject_empty_authorization_token() {
+        assert_eq!(
+            rejected(builder().authorization_token("")),
+            "authorization_token"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_graph_refs() {
+        for graph_ref in [
+            "",
+            "@current",
+            "my graph@current",
+            "graph@",
+            "graph@a@b",
+            "graph@a b",
+        ] {
+            let err = builder().graph_ref(graph_ref).build().unwrap_err();
+            assert!(
+                matches!(err, ApolloTracingConfigError::InvalidGraphRef { .. }),
+                "{graph_ref}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn reject_zero_max_traces() {
+        assert_eq!(rejected(builder().max_traces(0_usize)), "max_traces");
+    }
+
+    #[test]
+    fn reject_zero_channel_capacity() {
+        assert_eq!(
+            rejected(builder().channel_capacity(0_usize)),
+            "channel_capacity"
+        );
+    }
+
+    #[test]
+    fn reject_zero_durations() {
+        assert_eq!(
+            rejected(builder().flush_interval(Duration::ZERO)),
+            "flush_interval"
+        );
+        assert_eq!(
+            rejected(builder().send_timeout(Duration::ZERO)),
+            "send_timeout"
+        );
+    }
+
+    #[test]
+    fn reject_no_endpoints() {
+        assert_eq!(
+            rejected(builder().endpoints(Vec::<String>::new())),
+            "endpoints"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_spool_sizes() {
+        let spool = SpoolPolicy::new("spool");
+        for max_file_size in [0, spool.max_size + 1] {
+            let spool = SpoolPolicy {
+                max_file_size,
+                ..spool.clone()
+            };
+            assert_eq!(rejected(builder().spool(spool)), "spool");
+        }
+    }
+
+    #[test]
+    fn reject_zero_spool_durations() {
+        let spool = SpoolPolicy::new("spool");
+        let no_replay = SpoolPolicy {
+            replay_interval: Duration::ZERO,
+            ..spool.clone()
+        };
+        assert_eq!(rejected(builder().spool(no_replay)), "spool");
+        let no_age = SpoolPolicy {
+            max_age: Duration::ZERO,
+            ..spool
+        };
+        assert_eq!(rejected(builder().spool(no_age)), "spool");
+    }
+}
@@ -0,0 +1,225 @@
+//! # Errors reporting
+//!
+//! Errors end up in the traces with their message and their JSON representation, which can
+//! contain personal data or internal details. The [ErrorPolicy] decides what is sent.
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use async_graphql::{ServerError, Value};
+
+use crate::packages::serde_json;
+use crate::proto::reports::trace;
+
+type Rewrite = dyn Fn(ServerError) -> Option<ServerError> + Send + Sync;
+
+#[derive(Clone)]
+enum Mode {
+    Unmodified,
+    Masked,
+    Rewrite(Arc<Rewrite>),
+}
+
+/// Message of the errors sent with [ErrorPolicy::masked].
+const MASKED_MESSAGE: &str = "<masked>";
+
+/// Decide how errors are sent with the traces.
+///
+/// Errors which aren't sent don't count as errors in the stats either, which is handy for
+/// expected errors like authentication failures, see [ErrorPolicy::ignore_codes].
+#[derive(Clone)]
+pub struct ErrorPolicy {
+    mode: Mode,
+    ignored_codes: HashSet<String>,
+}
+
+impl Default for ErrorPolicy {
+    /// Errors are sent unmodified.
+    fn default() -> Self {
+        Self::unmodified()
+    }
+}
+
+impl std::fmt::Debug for ErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mode = match &self.mode {
+            Mode::Unmodified => "Unmodified",
+            Mode::Masked => "Masked",
+            Mode::Rewrite(_) => "Rewrite",
+        };
+        f.debug_struct("ErrorPolicy")
+            .field("mode", &mode)
+            .field("ignored_codes", &self.ignored_codes)
+            .finish()
+    }
+}
+
+impl ErrorPolicy {
+    fn with_mode(mode: Mode) -> Self {
+        Self {
+            mode,
+            ignored_codes: HashSet::new(),
+        }
+    }
+
+    /// Send errors as they are.
+    pub fn unmodified() -> Self {
+        Self::with_mode(Mode::Unmodified)
+    }
+
+    /// Replace the message of every error by `<masked>` and drop its extensions, only the
+    /// locations of the errors are kept.
+    pub fn masked() -> Self {
+        Self::with_mode(Mode::Masked)
+    }
+
+    /// Let `rewrite_error` change each error before it's sent, returning `None` drops it.
+    pub fn rewrite(
+        rewrite_error: impl Fn(ServerError) -> Option<ServerError> + Send + Sync + 'static,
+    ) -> Self {
+        Self::with_mode(Mode::Rewrite(Arc::new(rewrite_error)))
+    }
+
+    /// Drop the errors with one of these `code` in their extensions.
+    pub fn ignore_codes<I, C>(mut self, codes: I) -> Self
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<String>,
+    {
+        self.ignored_codes = codes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    fn is_ignored(&self, error: &ServerError) -> bool {
+        if self.ignored_codes.is_empty() {
+            return false;
+        }
+        match error.extensions.as_ref().and_then(|ext| ext.get("code")) {
+            Some(Value::String(code)) => self.ignored_codes.contains(code),
+            Some(Value::Enum(code)) => self.ignored_codes.contains(code.as_str()),
+            _ => false,
+        }
+    }
+
+    /// Convert the errors to their trace representation, following the policy.
+    pub(crate) fn trace_errors<'a>(
+        &self,
+        errors: impl IntoIterator<Item = &'a ServerError>,
+    ) -> Vec<trace::Error> {
+        errors
+            .into_iter()
+            .filter(|error| !self.is_ignored(error))
+            .filter_map(|error| match &self.mode {
+                Mode::Unmodified => Some(trace_error(error)),
+                Mode::Masked => {
+                    let mut masked = ServerError::new(MASKED_MESSAGE, None);
+                    masked.locations = error.locations.clone();
+                    Some(trace_error(&masked))
+                }
+                Mode::Rewrite(rewrite) => rewrite(error.clone()).map(|error| trace_error(&error)),
+            })
+            .collect()
+    }
+}
+
+/// Convert an async_graphql error into its trace representation.
+fn trace_error(e: &ServerError) -> trace::Error {
+    let json = match serde_json::to_string(e) {
+        Ok(content) => content,
+        Err(e) => format!("{{ \"error\": \"{e:?}\" }}"),
+    };
+    trace::Error {
+        message: e.message.clone(),
+        location: e
+            .locations
+            .iter()
+            .map(|x| trace::Location {
+                line: x.line as u32,
+                column: x.column as u32,
+                special_fields: protobuf::SpecialFields::default(),
+            })
+            .collect(),
+        json,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{ErrorExtensionValues, Pos};
+
+    use super::*;
+
+    fn error(message: &str, code: Option<&str>) -> ServerError {
+        let mut error = ServerError::new(message, Some(Pos { line: 1, column: 2 }));
+        if let Some(code) = code {
+            let mut extensions = ErrorExtensionValues::default();
+            extensions.set("code", code);
+            error.extensions = Some(extensions);
+        }
+        error
+    }
+
+    fn errors() -> Vec<ServerError> {
+        vec![
+            error("Not logged in", Some("UNAUTHENTICATED")),
+            error("User 42 not found", Some("NOT_FOUND")),
+            error("Database unreachable", None),
+        ]
+    }
+
+    fn messages(errors: &[trace::Error]) -> Vec<&str> {
+        errors.iter().map(|error| error.message.as_str()).collect()
+    }
+
+    #[test]
+    fn send_errors_unmodified_by_default() {
+        let errors = ErrorPolicy::default().trace_errors(&errors());
+        assert_eq!(
+            messages(&errors),
+            vec!["Not logged in", "User 42 not found", "Database unreachable"]
+        );
+        assert_eq!(errors[0].location[0].line, 1);
+        assert_eq!(errors[0].location[0].column, 2);
+        assert!(errors[0].json.contains("UNAUTHENTICATED"));
+    }
+
+    #[test]
+    fn mask_the_errors() {
+        let errors = ErrorPolicy::masked().trace_errors(&errors());
+        assert_eq!(messages(&errors), vec![MASKED_MESSAGE; 3]);
+        for error in &errors {
+            assert_eq!(error.location.len(), 1);
+            assert!(!error.json.contains("UNAUTHENTICATED"));
+            assert!(!error.json.contains("42"));
+        }
+    }
+
+    #[test]
+    fn rewrite_the_errors() {
+        let policy = ErrorPolicy::rewrite(|mut error| {
+            if error.extensions.is_some() {
+                error.message = "Client error".to_string();
+                Some(error)
+            } else {
+                None
+            }
+        });
+        let errors = policy.trace_errors(&errors());
+        assert_eq!(messages(&errors), vec!["Client error", "Client error"]);
+    }
+
+    #[test]
+    fn drop_the_ignored_codes() {
+        let policy = ErrorPolicy::unmodified().ignore_codes(["UNAUTHENTICATED"]);
+        assert_eq!(
+            messages(&policy.trace_errors(&errors())),
+            vec!["User 42 not found", "Database unreachable"]
+        );
+
+        let policy = ErrorPolicy::masked().ignore_codes(["UNAUTHENTICATED", "NOT_FOUND"]);
+        assert_eq!(
+            messages(&policy.trace_errors(&errors())),
+            vec![MASKED_MESSAGE]
+        );
+    }
+}
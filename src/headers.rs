@@ -0,0 +1,205 @@
+//! # Headers capture
+//!
+//! HTTP headers of the request and of the response can be sent with the traces. They often carry
+//! credentials, so none of them are sent unless a [HeadersCapture] policy says otherwise, and
+//! the sensitive ones are always stripped.
+use std::collections::{HashMap, HashSet};
+
+use crate::proto::reports::trace::http::Values;
+
+#[derive(Debug, Clone)]
+enum Policy {
+    None,
+    All,
+    Only(HashSet<String>),
+    AllExcept(HashSet<String>),
+}
+
+/// Credentials, stripped whatever the policy.
+const DEFAULT_REDACTED: [&str; 4] = ["authorization", "cookie", "set-cookie", "x-api-key"];
+
+/// Decide which HTTP headers are sent with the traces.
+///
+/// Request headers are given with [ApolloTracingDataExt](crate::ApolloTracingDataExt), response
+/// headers are the ones set on the async_graphql response. Header names are case insensitive.
+#[derive(Debug, Clone)]
+pub struct HeadersCapture {
+    policy: Policy,
+    redacted: HashSet<String>,
+}
+
+impl Default for HeadersCapture {
+    /// No header is sent.
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+fn lowercase_set<I, N>(names: I) -> HashSet<String>
+where
+    I: IntoIterator<Item = N>,
+    N: Into<String>,
+{
+    names
+        .into_iter()
+        .map(|name| name.into().to_ascii_lowercase())
+        .collect()
+}
+
+impl HeadersCapture {
+    fn with_policy(policy: Policy) -> Self {
+        Self {
+            policy,
+            redacted: lowercase_set(DEFAULT_REDACTED),
+        }
+    }
+
+    /// Don't send any header.
+    pub fn none() -> Self {
+        Self::with_policy(Policy::None)
+    }
+
+    /// Send every header but the redacted ones.
+    pub fn all() -> Self {
+        Self::with_policy(Policy::All)
+    }
+
+    /// Only send the headers with these names.
+    pub fn only<I, N>(names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        Self::with_policy(Policy::Only(lowercase_set(names)))
+    }
+
+    /// Send every header but the ones with these names.
+    pub fn all_except<I, N>(names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        Self::with_policy(Policy::AllExcept(lowercase_set(names)))
+    }
+
+    /// Never send these headers either, even when the policy allows them. `Authorization`,
+    /// `Cookie`, `Set-Cookie` and `X-Api-Key` are always redacted.
+    pub fn redact<I, N>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        self.redacted.extend(lowercase_set(names));
+        self
+    }
+
+    fn is_captured(&self, name: &str) -> bool {
+        if self.redacted.contains(name) {
+            return false;
+        }
+        match &self.policy {
+            Policy::None => false,
+            Policy::All => true,
+            Policy::Only(names) => names.contains(name),
+            Policy::AllExcept(names) => !names.contains(name),
+        }
+    }
+
+    /// Keep the allowed headers, grouping the values of a same header.
+    pub(crate) fn capture<'a>(
+        &self,
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> HashMap<String, Values> {
+        let mut captured: HashMap<String, Values> = HashMap::new();
+        if matches!(self.policy, Policy::None) {
+            return captured;
+        }
+
+        for (name, value) in headers {
+            let name = name.to_ascii_lowercase();
+            if self.is_captured(&name) {
+                captured
+                    .entry(name)
+                    .or_default()
+                    .value
+                    .push(value.to_string());
+            }
+        }
+        captured
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADERS: [(&str, &str); 5] = [
+        ("Accept", "application/json"),
+        ("Authorization", "Bearer secret"),
+        ("X-Request-Id", "1"),
+        ("x-forwarded-for", "10.0.0.1"),
+        ("X-Forwarded-For", "10.0.0.2"),
+    ];
+
+    /// Headers as sent in the trace, sorted by name.
+    fn captured(capture: &HeadersCapture) -> Vec<(String, Vec<String>)> {
+        let mut captured: Vec<_> = capture
+            .capture(HEADERS)
+            .into_iter()
+            .map(|(name, values)| (name, values.value))
+            .collect();
+        captured.sort();
+        captured
+    }
+
+    fn values(headers: &[(&str, &[&str])]) -> Vec<(String, Vec<String>)> {
+        headers
+            .iter()
+            .map(|(name, values)| {
+                let values = values.iter().map(|value| value.to_string()).collect();
+                (name.to_string(), values)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn send_no_header_by_default() {
+        assert!(captured(&HeadersCapture::default()).is_empty());
+    }
+
+    #[test]
+    fn send_every_header_but_the_credentials() {
+        assert_eq!(
+            captured(&HeadersCapture::all()),
+            values(&[
+                ("accept", &["application/json"]),
+                ("x-forwarded-for", &["10.0.0.1", "10.0.0.2"]),
+                ("x-request-id", &["1"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn send_only_the_allowed_headers() {
+        let capture = HeadersCapture::only(["X-Request-Id", "authorization"]);
+        assert_eq!(captured(&capture), values(&[("x-request-id", &["1"])]));
+    }
+
+    #[test]
+    fn drop_the_denied_headers() {
+        let capture = HeadersCapture::all_except(["x-forwarded-for"]);
+        assert_eq!(
+            captured(&capture),
+            values(&[("accept", &["application/json"]), ("x-request-id", &["1"])])
+        );
+    }
+
+    #[test]
+    fn drop_the_redacted_headers() {
+        let capture = HeadersCapture::all().redact(["X-Forwarded-For"]);
+        assert_eq!(
+            captured(&capture),
+            values(&[("accept", &["application/json"]), ("x-request-id", &["1"])])
+        );
+    }
+}
@@ -17,7 +17,20 @@
 //! ## Features
 //!
 //! * Fully support traces & errors
+//! * Field-level stats pre-aggregation, with an optional stats-only mode
+//! * Configurable trace sampling
+//! * Opt-in variables capture with privacy policies
+//! * Opt-in HTTP headers capture, credentials are always redacted
+//! * Error masking and rewriting before reporting
+//! * Cache policies from the async_graphql cache control hints
+//! * Automatic persisted queries hits and registrations
+//! * Subscriptions, with stats for each event
 //! * Batched traces transfer
+//! * Graceful shutdown flushing pending reports
+//! * Retries with backoff and circuit breaking when reports fail to be sent
+//! * Optional disk spool replaying the reports which couldn't be sent
+//! * Pluggable report sinks, to send reports somewhere else than Apollo Studio
+//! * Federated inline traces (ftv1) for subgraphs
 //! * Client segmentation
 //! * Tracing
 //! * Schema register protocol implemented
@@ -25,45 +38,72 @@
 //! ## Crate Features
 //!
 //! * `compression` - To enable GZIP Compression when sending traces to Apollo Studio.
+mod cache_policy;
 mod compression;
+mod config;
+mod errors;
+mod headers;
 mod proto;
+mod referenced_fields;
 pub mod register;
 mod report_aggregator;
 
-mod runtime;
 mod packages;
+mod persisted_query;
+mod runtime;
+mod sampler;
+mod signature;
+mod trace_tree;
+mod variables;
 
-use futures::SinkExt;
-use protobuf::{well_known_types::timestamp::Timestamp, EnumOrUnknown, MessageField};
-use report_aggregator::ReportAggregator;
-use runtime::spawn;
-use packages::serde_json;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use cache_policy::{FieldCachePolicies, SchemaCachePolicies};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use persisted_query::PersistedQuery;
+use protobuf::{well_known_types::timestamp::Timestamp, EnumOrUnknown, Message, MessageField};
+use report_aggregator::{ReportAggregator, TracedOperation};
+use trace_tree::TraceTreeBuilder;
+
+pub use config::{ApolloTracingConfig, ApolloTracingConfigBuilder, ApolloTracingConfigError};
+pub use proto::reports::Report;
+pub use report_aggregator::{
+    ApolloHttpSink, CircuitBreakerPolicy, DeliveryError, ReportSink, RetryPolicy, SpoolPolicy,
+    DEFAULT_REPORTING_URL,
+};
 
 #[macro_use]
 extern crate tracing;
 
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::RwLock;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
-use async_graphql::QueryPathSegment;
 use chrono::{DateTime, Utc};
 use futures::lock::Mutex;
-use std::convert::TryFrom;
 
 use async_graphql::extensions::{
-    Extension, ExtensionContext, ExtensionFactory, NextExecute, NextParseQuery, NextResolve,
-    ResolveInfo,
+    Extension, ExtensionContext, ExtensionFactory, NextExecute, NextParseQuery, NextPrepareRequest,
+    NextRequest, NextResolve, NextSubscribe, NextValidation, ResolveInfo,
 };
 use async_graphql::parser::types::{ExecutableDocument, OperationType, Selection};
-use async_graphql::{Response, ServerResult, Value, Variables};
+use async_graphql::QueryPathSegment;
+use async_graphql::{
+    Request, Response, ServerError, ServerResult, ValidationResult, Value, Variables,
+};
 use proto::reports::{
-    trace::{self, node, Node},
-    Trace,
+    trace::{self, Node},
+    ReferencedFieldsForType, Trace,
 };
 use std::convert::TryInto;
 
+pub use errors::ErrorPolicy;
+pub use headers::HeadersCapture;
 pub use proto::reports::trace::http::Method;
+pub use sampler::TraceSampler;
+pub use variables::VariablesCapture;
 
 /// Apollo Tracing Extension to send traces to Apollo Studio
 /// The extension to include to your `async_graphql` instance to connect with Apollo Studio.
@@ -78,27 +118,72 @@ pub use proto::reports::trace::http::Method;
 ///
 /// To add additional data to your metrics, you should add a ApolloTracingDataExt to your
 /// query_data when you process a query with async_graphql.
+///
+/// Clones share the same aggregator: keep one around to [flush](ApolloTracing::flush) or
+/// [shutdown](ApolloTracing::shutdown) it when your server stops, otherwise the traces not sent
+/// yet are lost.
+///
+/// Subgraphs of a federated graph don't report to Apollo Studio themselves, use
+/// [ApolloTracing::subgraph] to return their traces to the Router instead.
+///
+/// To report automatic persisted queries, register this extension before async_graphql's
+/// `ApolloPersistedQueries`, which hides them from the next extensions.
+#[derive(Clone)]
 pub struct ApolloTracing {
-    report: Arc<ReportAggregator>,
+    /// `None` for subgraphs, traces are returned inline in the response instead.
+    report: Option<Arc<ReportAggregator>>,
+    report_mode: ReportMode,
+    sampler: Arc<TraceSampler>,
+    variables: Arc<VariablesCapture>,
+    headers: Arc<HeadersCapture>,
+    errors: Arc<ErrorPolicy>,
+    /// Computed from each schema by its first request.
+    cache_policies: Arc<SchemaCachePolicies>,
+    status_code: Option<Arc<StatusCodeHook>>,
+}
+
+type StatusCodeHook = dyn Fn(&Response) -> Option<u32> + Send + Sync;
+
+/// Decide what is sent to Apollo Studio for each operation.
+///
+/// Every operation is aggregated into field-level stats, which are what Apollo Studio uses for
+/// its field usage and client breakdowns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReportMode {
+    /// Send the stats along with the full trace of each operation.
+    #[default]
+    TracesAndStats,
+    /// Only send the aggregated stats, traces never leave your server. Useful when traces could
+    /// contain data you are not allowed to share.
+    StatsOnly,
 }
 
 /// The structure where you can add additional context for Apollo Studio.
-/// This structure must be added to your query data.
+/// This structure must be added to your query data, or to the session data of a WebSocket
+/// connection for subscriptions, see [ApolloTracingDataExt::from_connection_init].
 ///
 /// It'll allow you to [segment your
 /// users](https://www.apollographql.com/docs/studio/client-awareness/)
 ///
 /// * `client_name` - You can segment your users by the client they are using to access your
-/// GraphQL API, it's really usefull when you have mobile and web users for instance. Usually we
-/// add a header `apollographql-client-name` to store this data. Apollo Studio will allow you to
-/// aggregate your metrics by Client Name.
+///   GraphQL API, it's really usefull when you have mobile and web users for instance. Usually we
+///   add a header `apollographql-client-name` to store this data. Apollo Studio will allow you to
+///   aggregate your metrics by Client Name.
 /// * `client_version` - You can segment your users by the client but it's usefull to also have the
-/// version your clients are using, especially when you are serving your API for mobile users,
-/// it'll allow you to follow metrics depending on which version your users are. Usually we add a
-/// header `apollographql-client-version` to store this data.
+///   version your clients are using, especially when you are serving your API for mobile users,
+///   it'll allow you to follow metrics depending on which version your users are. Usually we add a
+///   header `apollographql-client-version` to store this data.
 /// * `method` - The HTTP Method.
-/// * `status_code` - The status code return by your GraphQL API. It's a little weird to have to put it
-/// before executing the graphql function, it'll be changed later but usually it's just a 200.
+/// * `status_code` - The status code returned by your GraphQL API, when it's known before
+///   executing the request. It's derived from the response otherwise, see
+///   [ApolloTracing::with_status_code].
+/// * `request_headers` - The HTTP headers of the request, only the ones allowed by the
+///   [HeadersCapture] are sent.
+/// * `full_query_cache_hit` - Set it when the response is served from your full response cache,
+///   Apollo Studio reports the latency of these requests apart.
+/// * `include_federated_trace` - For [subgraphs](ApolloTracing::subgraph), set it when the Router
+///   asks for the trace of the request: the [FEDERATED_TRACING_HEADER] header is `ftv1`. The
+///   header is used instead when it's in the `request_headers`.
 #[derive(Debug, Clone, Default, derive_builder::Builder)]
 #[builder(pattern = "owned", setter(into, strip_option))]
 pub struct ApolloTracingDataExt {
@@ -110,8 +195,68 @@ pub struct ApolloTracingDataExt {
     pub method: Option<Method>,
     #[builder(default)]
     pub status_code: Option<u32>,
+    #[builder(default)]
+    pub request_headers: Option<Vec<(String, String)>>,
+    #[builder(default)]
+    pub full_query_cache_hit: bool,
+    #[builder(default)]
+    pub include_federated_trace: bool,
+}
+
+impl ApolloTracingDataExt {
+    /// Client awareness of a WebSocket connection, from the payload of its `connection_init`
+    /// message. The `apollographql-client-name` and `apollographql-client-version` values are
+    /// read at the root of the payload or in its `headers` object.
+    ///
+    /// Add it to the data returned by async_graphql's `on_connection_init`, it's then used for
+    /// every subscription of the connection.
+    pub fn from_connection_init(payload: &Value) -> Self {
+        let Value::Object(payload) = payload else {
+            return Self::default();
+        };
+        let headers = match payload.get("headers") {
+            Some(Value::Object(headers)) => Some(headers),
+            _ => None,
+        };
+        let read = |name: &str| {
+            let value = payload
+                .get(name)
+                .or_else(|| headers.and_then(|headers| headers.get(name)));
+            match value {
+                Some(Value::String(value)) => Some(value.clone()),
+                _ => None,
+            }
+        };
+
+        Self {
+            client_name: read("apollographql-client-name"),
+            client_version: read("apollographql-client-version"),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the Router asked for the trace of the request, from the [FEDERATED_TRACING_HEADER]
+    /// of the request when it's given.
+    fn includes_federated_trace(&self) -> bool {
+        let header = self
+            .request_headers
+            .iter()
+            .flatten()
+            .find_map(|(name, value)| {
+                name.eq_ignore_ascii_case(FEDERATED_TRACING_HEADER)
+                    .then_some(value)
+            });
+        match header {
+            Some(value) => value == "ftv1",
+            None => self.include_federated_trace,
+        }
+    }
 }
 
+/// Header sent by the Apollo Router to subgraphs, with the `ftv1` value, when it wants the trace
+/// of the request in the `ftv1` extension of the response.
+pub const FEDERATED_TRACING_HEADER: &str = "apollo-federation-include-trace";
+
 impl ApolloTracing {
     /// We initialize the ApolloTracing Extension by starting our aggregator async function which
     /// will receive every traces and send them to the Apollo Studio Ingress for processing
@@ -120,6 +265,9 @@ impl ApolloTracing {
     /// * hostname - Hostname like yourdomain-graphql-1.io
     /// * graph_ref - `ref@variant`  Graph reference with variant
     /// * release_name - Your release version or release name from Git for example
+    ///
+    /// The values aren't validated, prefer [ApolloTracing::from_config] which also gives control
+    /// over batching and delivery.
     pub fn new(
         authorization_token: String,
         hostname: String,
@@ -127,32 +275,154 @@ impl ApolloTracing {
         variant: String,
         service_version: String,
     ) -> ApolloTracing {
-        let report = ReportAggregator::initialize(
-            authorization_token,
-            hostname,
-            graph_id,
-            variant,
-            service_version,
-        );
+        Self::from_config(
+            ApolloTracingConfig::builder()
+                .authorization_token(authorization_token)
+                .graph_ref(format!("{graph_id}@{variant}"))
+                .hostname(hostname)
+                .service_version(service_version)
+                .build_unchecked(),
+        )
+    }
+
+    /// Initialize the extension from a validated [ApolloTracingConfig].
+    pub fn from_config(config: ApolloTracingConfig) -> ApolloTracing {
+        let report = ReportAggregator::initialize(config);
 
         ApolloTracing {
-            report: Arc::new(report),
+            report: Some(Arc::new(report)),
+            report_mode: ReportMode::default(),
+            sampler: Arc::new(TraceSampler::default()),
+            variables: Arc::new(VariablesCapture::default()),
+            headers: Arc::new(HeadersCapture::default()),
+            errors: Arc::new(ErrorPolicy::default()),
+            cache_policies: Arc::default(),
+            status_code: None,
+        }
+    }
+
+    /// Initialize the extension for a subgraph of a federated graph.
+    ///
+    /// Nothing is sent to Apollo Studio: when the Router asks for it, see
+    /// [ApolloTracingDataExt::include_federated_trace], the trace of the request is returned in
+    /// the `ftv1` extension of the response and the Router reports it. Requests are only
+    /// instrumented in this case, the [ReportMode] and the [TraceSampler] are ignored.
+    pub fn subgraph() -> ApolloTracing {
+        ApolloTracing {
+            report: None,
+            report_mode: ReportMode::default(),
+            sampler: Arc::new(TraceSampler::default()),
+            variables: Arc::new(VariablesCapture::default()),
+            headers: Arc::new(HeadersCapture::default()),
+            errors: Arc::new(ErrorPolicy::default()),
+            cache_policies: Arc::default(),
+            status_code: None,
+        }
+    }
+
+    /// Choose what is sent to Apollo Studio, see [ReportMode].
+    pub fn with_report_mode(mut self, report_mode: ReportMode) -> ApolloTracing {
+        self.report_mode = report_mode;
+        self
+    }
+
+    /// Choose which requests are instrumented and sent as traces, see [TraceSampler]. Every
+    /// request is sampled by default.
+    pub fn with_sampler(mut self, sampler: TraceSampler) -> ApolloTracing {
+        self.sampler = Arc::new(sampler);
+        self
+    }
+
+    /// Choose which variables are sent with the traces, see [VariablesCapture]. Every variable
+    /// is private by default.
+    pub fn with_variables(mut self, variables: VariablesCapture) -> ApolloTracing {
+        self.variables = Arc::new(variables);
+        self
+    }
+
+    /// Choose which HTTP headers are sent with the traces, see [HeadersCapture]. No header is
+    /// sent by default.
+    pub fn with_headers(mut self, headers: HeadersCapture) -> ApolloTracing {
+        self.headers = Arc::new(headers);
+        self
+    }
+
+    /// Choose how errors are sent with the traces, see [ErrorPolicy]. Errors are sent unmodified
+    /// by default.
+    pub fn with_errors(mut self, errors: ErrorPolicy) -> ApolloTracing {
+        self.errors = Arc::new(errors);
+        self
+    }
+
+    /// Give the status code of the HTTP response sent for a GraphQL response, for HTTP
+    /// integrations which decide it from the response. `None` falls back to the
+    /// [ApolloTracingDataExt::status_code] when it's set, then to `200` when the response has
+    /// data and `400` when it only has errors. The async_graphql integrations answer `200` even
+    /// for requests which failed to parse or validate, return it here to report it instead.
+    ///
+    /// Requests starting a subscription have no response, they're reported with the
+    /// [ApolloTracingDataExt::status_code] or `200`.
+    pub fn with_status_code(
+        mut self,
+        status_code: impl Fn(&Response) -> Option<u32> + Send + Sync + 'static,
+    ) -> ApolloTracing {
+        self.status_code = Some(Arc::new(status_code));
+        self
+    }
+
+    /// Send the traces aggregated so far to Apollo Studio, without waiting for the next batch.
+    ///
+    /// * `timeout` - How long to wait for the report to be sent.
+    pub async fn flush(&self, timeout: Duration) -> anyhow::Result<()> {
+        match &self.report {
+            Some(report) => report.flush(timeout).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Send every trace left, including the ones still waiting in the channel, then stop the
+    /// background task. Traces of requests executed after this call are dropped.
+    ///
+    /// Call it during your graceful shutdown so deployments don't leave a gap in Apollo Studio.
+    ///
+    /// * `timeout` - How long to wait for the last report to be sent, the background task is
+    ///   aborted past it.
+    pub async fn shutdown(&self, timeout: Duration) -> anyhow::Result<()> {
+        match &self.report {
+            Some(report) => report.shutdown(timeout).await,
+            None => Ok(()),
         }
     }
 }
 
 impl ExtensionFactory for ApolloTracing {
     fn create(&self) -> Arc<dyn Extension> {
-        Arc::new(ApolloTracingExtension {
+        Arc::new(ApolloTracingExtension(Arc::new(ExtensionState {
             inner: Mutex::new(Inner {
                 start_time: Utc::now(),
                 end_time: Utc::now(),
             }),
             report: self.report.clone(),
-            nodes: RwLock::new(HashMap::new()),
-            root_node: Arc::new(RwLock::new(Node::default())),
-            operation_name: RwLock::new("schema".to_string()),
-        })
+            report_mode: self.report_mode,
+            sampler: self.sampler.clone(),
+            variables: self.variables.clone(),
+            headers: self.headers.clone(),
+            errors: self.errors.clone(),
+            cache_policies: self.cache_policies.clone(),
+            field_cache_policies: OnceLock::new(),
+            status_code: self.status_code.clone(),
+            instrumented: AtomicBool::new(false),
+            tree: TraceTreeBuilder::default(),
+            tracing_data: RwLock::new(ApolloTracingDataExt::default()),
+            requested_operation_name: RwLock::new(None),
+            persisted_query: RwLock::new(None),
+            operation_type: RwLock::new(None),
+            query: RwLock::new(String::new()),
+            variables_json: RwLock::new(HashMap::new()),
+            operation_key: RwLock::new("schema".to_string()),
+            referenced_fields_by_type: RwLock::new(HashMap::new()),
+            unexecuted_key: RwLock::new(None),
+        })))
     }
 }
 
@@ -161,67 +431,87 @@ struct Inner {
     end_time: DateTime<Utc>,
 }
 
-struct ApolloTracingExtension {
+/// The extension of one request. Its state is shared with the stream of the subscriptions,
+/// which outlives the hooks.
+#[derive(Clone)]
+struct ApolloTracingExtension(Arc<ExtensionState>);
+
+impl std::ops::Deref for ApolloTracingExtension {
+    type Target = ExtensionState;
+
+    fn deref(&self) -> &ExtensionState {
+        &self.0
+    }
+}
+
+struct ExtensionState {
     inner: Mutex<Inner>,
-    report: Arc<ReportAggregator>,
-    nodes: RwLock<HashMap<String, Arc<RwLock<Node>>>>,
-    root_node: Arc<RwLock<Node>>,
-    operation_name: RwLock<String>,
+    report: Option<Arc<ReportAggregator>>,
+    report_mode: ReportMode,
+    sampler: Arc<TraceSampler>,
+    variables: Arc<VariablesCapture>,
+    headers: Arc<HeadersCapture>,
+    errors: Arc<ErrorPolicy>,
+    cache_policies: Arc<SchemaCachePolicies>,
+    /// Policies of the schema executing the request, looked up by its first field.
+    field_cache_policies: OnceLock<Arc<FieldCachePolicies>>,
+    status_code: Option<Arc<StatusCodeHook>>,
+    /// Whether resolvers are instrumented for this request, decided by the [TraceSampler].
+    instrumented: AtomicBool,
+    /// Fields resolved by the current execution.
+    tree: TraceTreeBuilder,
+    /// Data added by the user to the request, see [ApolloTracingDataExt].
+    tracing_data: RwLock<ApolloTracingDataExt>,
+    /// Operation name sent by the client, used to select the operation to report.
+    requested_operation_name: RwLock<Option<String>>,
+    /// Automatic persisted query sent by the client, if any.
+    persisted_query: RwLock<Option<PersistedQuery>>,
+    /// Type of the operation to execute, once the query is parsed.
+    operation_type: RwLock<Option<OperationType>>,
+    /// Query sent by the client, reported when it can't be executed.
+    query: RwLock<String>,
+    /// Variables sent by the client, as reported following the [VariablesCapture].
+    variables_json: RwLock<HashMap<String, String>>,
+    /// Key of the operation in the report, see [signature::usage_reporting_key].
+    operation_key: RwLock<String>,
+    /// See [referenced_fields::referenced_fields_by_type].
+    referenced_fields_by_type: RwLock<HashMap<String, ReferencedFieldsForType>>,
+    /// Set when the operation won't be executed, with the key it's reported under.
+    unexecuted_key: RwLock<Option<&'static str>>,
 }
 
-#[async_trait::async_trait]
-impl Extension for ApolloTracingExtension {
-    #[instrument(level = "debug", skip(self, ctx, next))]
-    async fn parse_query(
-        &self,
-        ctx: &ExtensionContext<'_>,
-        query: &str,
-        variables: &Variables,
-        next: NextParseQuery<'_>,
-    ) -> ServerResult<ExecutableDocument> {
-        let document = next.run(ctx, query, variables).await?;
-        let is_schema = document
-            .operations
-            .iter()
-            .filter(|(_, operation)| operation.node.ty == OperationType::Query)
-            .any(|(_, operation)| operation.node.selection_set.node.items.iter().any(|selection| matches!(&selection.node, Selection::Field(field) if field.node.name.node == "__schema")));
-        if !is_schema {
-            let result: String =
-                ctx.stringify_execute_doc(&document, &Variables::from_json(serde_json::from_str("{}").unwrap()));
-            let name = document
-                .operations
-                .iter()
-                .next()
-                .and_then(|x| x.0)
-                .map(|x| x.as_str())
-                .unwrap_or("no_name");
-            let query_type = format!("# {name}\n {query}", name = name, query = result);
-            *self.operation_name.write().unwrap() = query_type;
-        }
-        Ok(document)
+/// Keys used by Apollo for operations which couldn't be executed.
+const PARSE_FAILURE_KEY: &str = "## GraphQLParseFailure\n";
+const VALIDATION_FAILURE_KEY: &str = "## GraphQLValidationFailure\n";
+const UNKNOWN_OPERATION_NAME_KEY: &str = "## GraphQLUnknownOperationName\n";
+
+/// Operation subtypes of the subscriptions: the request which starts the subscription, then each
+/// event it sends.
+const SUBSCRIPTION_REQUEST: &str = "subscription-request";
+const SUBSCRIPTION_EVENT: &str = "subscription-event";
+
+/// Status code of the HTTP response when neither the integration nor the user gave it: `200`
+/// when there is data, `400` when there are only errors, like a request which couldn't be parsed.
+fn default_status_code(resp: &Response) -> u32 {
+    if resp.data == Value::Null && !resp.errors.is_empty() {
+        400
+    } else {
+        200
     }
+}
 
-    #[instrument(level = "debug", skip(self, ctx, next))]
-    async fn execute(
+impl ExtensionState {
+    /// Create the trace of the request, without its tree of nodes. Its field execution weight is
+    /// left to 0, for requests without field-level instrumentation. `resp` is `None` for the
+    /// requests starting a subscription, which have no response of their own.
+    fn new_trace(
         &self,
-        ctx: &ExtensionContext<'_>,
+        resp: Option<&Response>,
         operation_name: Option<&str>,
-        next: NextExecute<'_>,
-    ) -> Response {
-        let start_time = Utc::now();
-        self.inner.lock().await.start_time = start_time;
-
-        let resp = next.run(ctx, operation_name).await;
-        // Here every responses are executed
-        // The next execute should aggregates a node a not a trace
-        let mut inner = self.inner.lock().await;
-        inner.end_time = Utc::now();
-
-        let tracing_extension = ctx
-            .data::<ApolloTracingDataExt>()
-            .ok()
-            .cloned()
-            .unwrap_or_default();
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Trace {
+        let tracing_extension = self.tracing_data.read().unwrap().clone();
 
         let client_name = tracing_extension
             .client_name
@@ -232,15 +522,54 @@ impl Extension for ApolloTracingExtension {
         let method = tracing_extension
             .method
             .or(<Method as protobuf::Enum>::from_str("UNKNOWN"));
-        let status_code = tracing_extension.status_code.unwrap_or(0);
+        let status_code = resp
+            .zip(self.status_code.as_ref())
+            .and_then(|(resp, status_code)| status_code(resp))
+            .or(tracing_extension.status_code)
+            .unwrap_or_else(|| resp.map_or(200, default_status_code));
+        let request_headers = tracing_extension
+            .request_headers
+            .as_ref()
+            .map(|headers| {
+                self.headers.capture(
+                    headers
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.as_str())),
+                )
+            })
+            .unwrap_or_default();
+        let response_headers = resp
+            .map(|resp| {
+                self.headers.capture(
+                    resp.http_headers
+                        .iter()
+                        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?))),
+                )
+            })
+            .unwrap_or_default();
 
+        let persisted_query = self.persisted_query.read().unwrap();
+        let operation_type = *self.operation_type.read().unwrap();
+        // Every event of a subscription is traced with the variables of its request.
+        let variables_json = if operation_type == Some(OperationType::Subscription) {
+            self.variables_json.read().unwrap().clone()
+        } else {
+            std::mem::take(&mut *self.variables_json.write().unwrap())
+        };
         let mut trace: Trace = Trace {
             client_name,
             client_version,
-            duration_ns: (inner.end_time - inner.start_time)
+            duration_ns: (end_time - start_time)
                 .num_nanoseconds()
                 .map(|x| x.try_into().unwrap())
                 .unwrap_or(0),
+            cache_policy: resp
+                .map(|resp| cache_policy::response_cache_policy(&resp.cache_control))
+                .unwrap_or_default(),
+            full_query_cache_hit: tracing_extension.full_query_cache_hit,
+            persisted_query_hit: persisted_query.as_ref().is_some_and(|pq| !pq.register),
+            persisted_query_register: persisted_query.as_ref().is_some_and(|pq| pq.register),
+            operation_type: operation_type.map(|ty| ty.to_string()).unwrap_or_default(),
             ..Default::default()
         };
 
@@ -248,6 +577,7 @@ impl Extension for ApolloTracingExtension {
             operation_name: operation_name
                 .map(|x| x.to_string())
                 .unwrap_or_else(|| "no operation".to_string()),
+            variables_json,
             ..Default::default()
         })
         .into();
@@ -255,39 +585,304 @@ impl Extension for ApolloTracingExtension {
         trace.http = Some(trace::HTTP {
             method: EnumOrUnknown::new(method.unwrap()),
             status_code,
+            request_headers,
+            response_headers,
             ..Default::default()
         })
         .into();
 
         trace.end_time = MessageField::some(Timestamp {
-            nanos: inner.end_time.timestamp_subsec_nanos().try_into().unwrap(),
-            seconds: inner.end_time.timestamp(),
+            nanos: end_time.timestamp_subsec_nanos().try_into().unwrap(),
+            seconds: end_time.timestamp(),
             special_fields: Default::default(),
         });
 
         trace.start_time =
             protobuf::MessageField::some(protobuf::well_known_types::timestamp::Timestamp {
-                nanos: inner
-                    .start_time
-                    .timestamp_subsec_nanos()
-                    .try_into()
-                    .unwrap(),
-                seconds: inner.start_time.timestamp(),
+                nanos: start_time.timestamp_subsec_nanos().try_into().unwrap(),
+                seconds: start_time.timestamp(),
                 special_fields: Default::default(),
             });
 
-        let root_node = self.root_node.read().unwrap();
-        trace.root = Some(root_node.clone()).into();
+        trace
+    }
+
+    /// Report the operation when it failed before being executed, with its errors on the root node,
+    /// so broken queries show up in Apollo Studio too.
+    fn send_unexecuted(&self, resp: &Response, start_time: DateTime<Utc>) {
+        let unexecuted_key = self.unexecuted_key.write().unwrap().take();
+        let (Some(key), Some(report)) = (unexecuted_key, &self.report) else {
+            return;
+        };
+
+        let operation_name = self.requested_operation_name.read().unwrap().clone();
+        let mut trace = self.new_trace(
+            Some(resp),
+            operation_name.as_deref(),
+            start_time,
+            Utc::now(),
+        );
+        trace.field_execution_weight = 1.;
+        trace.unexecutedOperationBody = std::mem::take(&mut *self.query.write().unwrap());
+        trace.unexecutedOperationName = operation_name.unwrap_or_default();
+        trace.root = Some(Node {
+            error: self.errors.trace_errors(&resp.errors),
+            ..Default::default()
+        })
+        .into();
+
+        self.send(
+            report,
+            TracedOperation {
+                key: key.to_string(),
+                trace,
+                keep_trace: true,
+                referenced_fields_by_type: HashMap::new(),
+                persisted_query_id: None,
+            },
+        );
+    }
+
+    /// Report the request starting a subscription, once it's validated. Its fields are only
+    /// resolved with the events, so it has no tree.
+    fn send_subscription_request(&self, start_time: DateTime<Utc>) {
+        let Some(report) = &self.report else {
+            return;
+        };
+
+        let operation_name = self.requested_operation_name.read().unwrap().clone();
+        let mut trace = self.new_trace(None, operation_name.as_deref(), start_time, Utc::now());
+        trace.operation_subtype = SUBSCRIPTION_REQUEST.to_string();
+        trace.root = Some(Node::default()).into();
+        // No field is resolved by the request, its empty tree is complete when it's sampled.
+        let field_execution_weight = self.sampler.sample(operation_name.as_deref());
+        if let Some(weight) = field_execution_weight {
+            trace.field_execution_weight = weight;
+        }
+
+        self.send(
+            report,
+            TracedOperation {
+                key: self.operation_key.read().unwrap().clone(),
+                trace,
+                keep_trace: field_execution_weight.is_some(),
+                referenced_fields_by_type: self.referenced_fields_by_type.read().unwrap().clone(),
+                persisted_query_id: self.persisted_query_id(),
+            },
+        );
+    }
 
-        let mut sender = self.report.sender();
+    fn persisted_query_id(&self) -> Option<String> {
+        self.persisted_query
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|pq| pq.id.clone())
+    }
 
-        let operation_name = self.operation_name.read().unwrap().clone();
+    /// Send the operation to the aggregator, without waiting for it.
+    fn send(&self, report: &ReportAggregator, mut operation: TracedOperation) {
+        operation.keep_trace &= self.report_mode == ReportMode::TracesAndStats;
+        report.send_trace(operation);
+    }
+}
 
-        let _handle = spawn(async move {
-            if let Err(e) = sender.send((operation_name, trace)).await {
-                error!(error = ?e);
+#[async_trait::async_trait]
+impl Extension for ApolloTracingExtension {
+    #[instrument(level = "debug", skip(self, ctx, next))]
+    async fn request(&self, ctx: &ExtensionContext<'_>, next: NextRequest<'_>) -> Response {
+        let start_time = Utc::now();
+        let resp = next.run(ctx).await;
+        self.send_unexecuted(&resp, start_time);
+        resp
+    }
+
+    fn subscribe<'s>(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        stream: BoxStream<'s, Response>,
+        next: NextSubscribe<'_>,
+    ) -> BoxStream<'s, Response> {
+        let stream = next.run(ctx, stream);
+        if self.report.is_none() {
+            return stream;
+        }
+
+        // The request hook isn't called for streams, every response is looked at here instead.
+        let start_time = Utc::now();
+        let extension = self.clone();
+        stream
+            .inspect(move |resp| extension.send_unexecuted(resp, start_time))
+            .boxed()
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, request, next))]
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        if let Ok(tracing_data) = ctx.data::<ApolloTracingDataExt>() {
+            *self.tracing_data.write().unwrap() = tracing_data.clone();
+        }
+        *self.persisted_query.write().unwrap() = PersistedQuery::from_request(&request);
+        self.inner.lock().await.start_time = Utc::now();
+        let request = next.run(ctx, request).await?;
+        *self.requested_operation_name.write().unwrap() = request.operation_name.clone();
+        Ok(request)
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, next))]
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        *self.query.write().unwrap() = query.to_string();
+        if self.variables.is_enabled() {
+            *self.variables_json.write().unwrap() = self.variables.capture(variables);
+        }
+        let document = match next.run(ctx, query, variables).await {
+            Ok(document) => document,
+            Err(err) => {
+                *self.unexecuted_key.write().unwrap() = Some(PARSE_FAILURE_KEY);
+                return Err(err);
             }
-        });
+        };
+        let requested_operation_name = self.requested_operation_name.read().unwrap().clone();
+        *self.operation_type.write().unwrap() =
+            signature::find_operation(&document.operations, requested_operation_name.as_deref())
+                .map(|(_, operation)| operation.ty);
+        let is_schema = document
+            .operations
+            .iter()
+            .filter(|(_, operation)| operation.node.ty == OperationType::Query)
+            .any(|(_, operation)| operation.node.selection_set.node.items.iter().any(|selection| matches!(&selection.node, Selection::Field(field) if field.node.name.node == "__schema")));
+        if !is_schema {
+            match signature::usage_reporting_key(&document, requested_operation_name.as_deref()) {
+                Some(key) => *self.operation_key.write().unwrap() = key,
+                None => *self.unexecuted_key.write().unwrap() = Some(UNKNOWN_OPERATION_NAME_KEY),
+            }
+            *self.referenced_fields_by_type.write().unwrap() =
+                referenced_fields::referenced_fields_by_type(
+                    &ctx.schema_env.registry,
+                    &document,
+                    requested_operation_name.as_deref(),
+                );
+        }
+        Ok(document)
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, next))]
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let result = next.run(ctx).await;
+        if result.is_err() {
+            *self.unexecuted_key.write().unwrap() = Some(VALIDATION_FAILURE_KEY);
+        } else if *self.operation_type.read().unwrap() == Some(OperationType::Subscription)
+            && self.unexecuted_key.read().unwrap().is_none()
+        {
+            let start_time = self.inner.lock().await.start_time;
+            self.send_subscription_request(start_time);
+        }
+        result
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, next))]
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        // The operation is executed, it's reported as usual.
+        *self.unexecuted_key.write().unwrap() = None;
+        std::mem::take(&mut *self.query.write().unwrap());
+
+        let start_time = Utc::now();
+        self.inner.lock().await.start_time = start_time;
+        self.tree.start(start_time);
+
+        // Subscriptions are executed once for each of their events, each event has its own tree.
+        let is_subscription_event =
+            *self.operation_type.read().unwrap() == Some(OperationType::Subscription);
+
+        // Subgraphs are instrumented when the Router asks for the trace, it does the sampling.
+        let field_execution_weight = match &self.report {
+            Some(_) => self.sampler.sample(operation_name),
+            None => self
+                .tracing_data
+                .read()
+                .unwrap()
+                .includes_federated_trace()
+                .then_some(1.),
+        };
+        self.instrumented
+            .store(field_execution_weight.is_some(), Ordering::Relaxed);
+
+        let mut resp = next.run(ctx, operation_name).await;
+        // Here every responses are executed
+        // The next execute should aggregates a node a not a trace
+        let mut inner = self.inner.lock().await;
+        inner.end_time = Utc::now();
+
+        let mut trace = self.new_trace(
+            Some(&resp),
+            operation_name,
+            inner.start_time,
+            inner.end_time,
+        );
+        if let Some(weight) = field_execution_weight {
+            trace.field_execution_weight = weight;
+        }
+        if is_subscription_event {
+            trace.operation_subtype = SUBSCRIPTION_EVENT.to_string();
+        }
+
+        let keep_trace = if field_execution_weight.is_some() {
+            trace.root = Some(self.tree.build(&resp.errors, &self.errors)).into();
+            true
+        } else {
+            // Without instrumentation, errors can only be reported on the root node.
+            let error = self.errors.trace_errors(&resp.errors);
+            let has_errors = !error.is_empty();
+            trace.root = Some(Node {
+                error,
+                ..Default::default()
+            })
+            .into();
+            self.sampler.keeps_errors() && has_errors
+        };
+
+        let Some(report) = &self.report else {
+            if field_execution_weight.is_some() {
+                let ftv1 = BASE64_STANDARD.encode(inline_trace(trace).write_to_bytes().unwrap());
+                resp.extensions
+                    .insert("ftv1".to_string(), Value::String(ftv1));
+            }
+            return resp;
+        };
+
+        self.send(
+            report,
+            TracedOperation {
+                key: self.operation_key.read().unwrap().clone(),
+                trace,
+                keep_trace,
+                referenced_fields_by_type: if is_subscription_event {
+                    self.referenced_fields_by_type.read().unwrap().clone()
+                } else {
+                    std::mem::take(&mut self.referenced_fields_by_type.write().unwrap())
+                },
+                persisted_query_id: self.persisted_query_id(),
+            },
+        );
         resp
     }
 
@@ -298,107 +893,417 @@ impl Extension for ApolloTracingExtension {
         info: ResolveInfo<'_>,
         next: NextResolve<'_>,
     ) -> ServerResult<Option<Value>> {
-        // We do create a node when it's invoked which we insert at the right place inside the
-        // struct.
-
-        let path = info.path_node.to_string_vec().join(".");
-        let field_name = info.path_node.field_name().to_string();
-        let parent_type = info.parent_type.to_string();
-        let _return_type = info.return_type.to_string();
-        let start_time = Utc::now() - self.inner.lock().await.start_time;
-        let path_node = info.path_node;
+        if !self.instrumented.load(Ordering::Relaxed) {
+            return next.run(ctx, info).await;
+        }
 
-        let node: Node = Node {
-            end_time: 0,
-            id: match path_node.segment {
-                QueryPathSegment::Name(name) => Some(node::Id::ResponseName(name.to_string())),
-                QueryPathSegment::Index(index) => {
-                    Some(node::Id::Index(index.try_into().unwrap_or(0)))
-                }
-            },
-            start_time: match start_time
-                .num_nanoseconds()
-                .and_then(|x| u64::try_from(x).ok())
-            {
-                Some(duration) => duration,
-                None => Utc::now()
-                    .timestamp_nanos_opt()
-                    .unwrap_or_default()
-                    .try_into()
-                    .unwrap_or_default(),
-            },
-            parent_type: parent_type.to_string(),
-            original_field_name: field_name,
+        let entered = self.tree.enter(info.path_node);
+        // Items of lists are resolved too, but like Apollo Server their node only has an index.
+        // Their errors are the ones of their children, async_graphql only changes their path.
+        if let QueryPathSegment::Index(_) = info.path_node.segment {
+            let res = next.run(ctx, info).await;
+            self.tree.record(entered, Node::default(), None);
+            return res;
+        }
+
+        let mut node = Node {
+            start_time: self.tree.elapsed_ns(),
+            cache_policy: self
+                .field_cache_policies
+                .get_or_init(|| self.cache_policies.get(ctx.schema_env))
+                .get(info.parent_type, info.name),
+            parent_type: info.parent_type.to_string(),
+            original_field_name: info.name.to_string(),
+            type_: info.return_type.to_string(),
             ..Default::default()
         };
 
-        let node = Arc::new(RwLock::new(node));
-        self.nodes.write().unwrap().insert(path, node.clone());
-        let parent_node = path_node.parent.map(|x| x.to_string_vec().join("."));
-        // Use the path to create a new node
-        // https://github.com/apollographql/apollo-server/blob/291c17e255122d4733b23177500188d68fac55ce/packages/apollo-server-core/src/plugin/traceTreeBuilder.ts
-        let res = match next.run(ctx, info).await {
-            Ok(res) => Ok(res),
-            Err(e) => {
-                let json = match serde_json::to_string(&e) {
-                    Ok(content) => content,
-                    Err(e) => format!("{{ \"error\": \"{e:?}\" }}"),
-                };
-                let error = trace::Error {
-                    message: e.message.clone(),
-                    location: e
-                        .locations
-                        .clone()
-                        .into_iter()
-                        .map(|x| trace::Location {
-                            line: x.line as u32,
-                            column: x.column as u32,
-                            special_fields: protobuf::SpecialFields::default(),
-                        })
-                        .collect(),
-                    json,
-                    ..Default::default()
-                };
-
-                node.write().unwrap().error = vec![error];
-                Err(e)
+        let path_node = info.path_node;
+        let res = next.run(ctx, info).await;
+        let error = res
+            .as_ref()
+            .err()
+            .filter(|e| trace_tree::is_raised_at(path_node, e))
+            .cloned();
+        node.end_time = self.tree.elapsed_ns();
+        self.tree.record(entered, node, error);
+
+        res
+    }
+}
+
+/// Keep the fields of the trace which can be sent by a subgraph to the Router, the others are
+/// filled by the Router itself.
+fn inline_trace(trace: Trace) -> Trace {
+    Trace {
+        start_time: trace.start_time,
+        end_time: trace.end_time,
+        duration_ns: trace.duration_ns,
+        root: trace.root,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{value, Object, Request, Schema, Subscription};
+    use futures::Stream;
+
+    use super::*;
+    use crate::proto::reports::trace::{cache_policy::Scope, CachePolicy};
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn value(&self) -> i32 {
+            1
+        }
+
+        #[graphql(cache_control(max_age = 60))]
+        async fn cached(&self) -> i32 {
+            1
+        }
+    }
+
+    struct Mutation;
+
+    #[Object]
+    impl Mutation {
+        async fn set(&self, value: i32) -> i32 {
+            value
+        }
+    }
+
+    struct Subscription;
+
+    #[Subscription]
+    impl Subscription {
+        async fn values(&self, count: i32) -> impl Stream<Item = i32> {
+            futures::stream::iter(0..count)
+        }
+    }
+
+    /// Keeps the reports it's sent.
+    #[derive(Clone, Default)]
+    struct CapturingSink(Arc<std::sync::Mutex<Vec<Report>>>);
+
+    #[async_trait::async_trait]
+    impl ReportSink for CapturingSink {
+        async fn send(&self, report: &Report) -> Result<(), DeliveryError> {
+            self.0.lock().unwrap().push(report.clone());
+            Ok(())
+        }
+    }
+
+    /// Stands in for async_graphql's `ApolloPersistedQueries`, which knows every query by the
+    /// hash `abc`.
+    struct PersistedQueries;
+
+    impl ExtensionFactory for PersistedQueries {
+        fn create(&self) -> Arc<dyn Extension> {
+            Arc::new(PersistedQueries)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Extension for PersistedQueries {
+        async fn prepare_request(
+            &self,
+            ctx: &ExtensionContext<'_>,
+            mut request: Request,
+            next: NextPrepareRequest<'_>,
+        ) -> ServerResult<Request> {
+            if request.extensions.remove("persistedQuery").is_some() && request.query.is_empty() {
+                request.query = "query Value { value }".to_string();
             }
+            next.run(ctx, request).await
+        }
+    }
+
+    type TestSchema = Schema<Query, Mutation, Subscription>;
+
+    fn schema() -> (TestSchema, ApolloTracing, CapturingSink) {
+        let sink = CapturingSink::default();
+        let config = ApolloTracingConfig::builder()
+            .authorization_token("token")
+            .graph_ref("test@current")
+            .sink(sink.clone())
+            .build()
+            .unwrap();
+        let tracing = ApolloTracing::from_config(config);
+        let schema = Schema::build(Query, Mutation, Subscription)
+            .extension(tracing.clone())
+            .extension(PersistedQueries)
+            .finish();
+        (schema, tracing, sink)
+    }
+
+    /// Wait for the report of the operations executed so far.
+    async fn sent_report(tracing: ApolloTracing, sink: CapturingSink) -> Report {
+        tracing.shutdown(Duration::from_secs(5)).await.unwrap();
+        let mut reports = sink.0.lock().unwrap().clone();
+        assert_eq!(reports.len(), 1);
+        reports.remove(0)
+    }
+
+    /// Execute the requests, then return the report of their operations.
+    async fn report(requests: Vec<Request>) -> Report {
+        let (schema, tracing, sink) = schema();
+        for request in requests {
+            schema.execute(request).await;
+        }
+        sent_report(tracing, sink).await
+    }
+
+    /// The only trace of the operation with this key.
+    fn trace<'a>(report: &'a Report, key: &str) -> &'a Trace {
+        let traces = &report.traces_per_query[key].trace;
+        assert_eq!(traces.len(), 1);
+        &traces[0]
+    }
+
+    #[tokio::test]
+    async fn report_the_body_of_unexecuted_operations() {
+        let report = report(vec![
+            Request::new("query Broken {").operation_name("Broken"),
+            Request::new("query Invalid { unknown }").operation_name("Invalid"),
+            Request::new("query Value { value }").operation_name("Other"),
+        ])
+        .await;
+
+        let cases = [
+            (PARSE_FAILURE_KEY, "query Broken {", "Broken"),
+            (
+                VALIDATION_FAILURE_KEY,
+                "query Invalid { unknown }",
+                "Invalid",
+            ),
+            (UNKNOWN_OPERATION_NAME_KEY, "query Value { value }", "Other"),
+        ];
+        for (key, body, name) in cases {
+            let trace = trace(&report, key);
+            assert_eq!(trace.unexecutedOperationBody, body);
+            assert_eq!(trace.unexecutedOperationName, name);
+            assert_eq!(trace.root.error.len(), 1, "{key}");
+            assert!(trace.root.child.is_empty());
+        }
+        assert_eq!(report.operation_count, 3);
+    }
+
+    #[tokio::test]
+    async fn report_executed_operations_by_signature() {
+        let report = report(vec![Request::new("query Value { value }")]).await;
+        let trace = trace(&report, "# Value\nquery Value{value}");
+        assert!(trace.unexecutedOperationBody.is_empty());
+        assert!(trace.unexecutedOperationName.is_empty());
+        assert!(trace.root.error.is_empty());
+        assert_eq!(trace.root.child[0].response_name(), "value");
+    }
+
+    /// The `ftv1` extension of the response of a subgraph.
+    async fn ftv1(data: ApolloTracingDataExt) -> Option<Trace> {
+        let schema = Schema::build(Query, Mutation, Subscription)
+            .extension(ApolloTracing::subgraph())
+            .finish();
+        let resp = schema
+            .execute(Request::new("query Value { value }").data(data))
+            .await;
+        let Value::String(ftv1) = resp.extensions.get("ftv1")? else {
+            panic!("ftv1 isn't a string");
         };
-        let end_time = Utc::now() - self.inner.lock().await.start_time;
+        let bytes = BASE64_STANDARD.decode(ftv1).unwrap();
+        Some(Trace::parse_from_bytes(&bytes).unwrap())
+    }
 
-        node.write().unwrap().end_time = match end_time
-            .num_nanoseconds()
-            .and_then(|x| u64::try_from(x).ok())
-        {
-            Some(duration) => duration,
-            None => Utc::now()
-                .timestamp_nanos_opt()
-                .unwrap_or_default()
-                .try_into()
-                .unwrap_or_default(),
+    fn with_header(value: &str) -> ApolloTracingDataExt {
+        ApolloTracingDataExt {
+            client_name: Some("web".to_string()),
+            request_headers: Some(vec![(
+                "Apollo-Federation-Include-Trace".to_string(),
+                value.to_string(),
+            )]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn return_the_trace_asked_by_the_router() {
+        let trace = ftv1(with_header("ftv1")).await.unwrap();
+        assert!(trace.start_time.is_some());
+        assert!(trace.end_time.is_some());
+        assert!(trace.duration_ns > 0);
+        let field = &trace.root.child[0];
+        assert_eq!(field.response_name(), "value");
+        assert_eq!(field.parent_type, "Query");
+        assert_eq!(field.type_, "Int!");
+
+        // The Router fills everything else.
+        let expected = Trace {
+            start_time: trace.start_time.clone(),
+            end_time: trace.end_time.clone(),
+            duration_ns: trace.duration_ns,
+            root: trace.root.clone(),
+            ..Default::default()
         };
+        assert_eq!(trace, expected);
+    }
 
-        match parent_node {
-            None => {
-                let mut root_node = self.root_node.write().unwrap();
-                let child = &mut root_node.child;
-                let node = node.read().unwrap();
-                // Can't copy or pass a ref to Protobuf
-                // So we clone
-                child.push(node.clone());
-            }
-            Some(parent) => {
-                let nodes = self.nodes.read().unwrap();
-                let node_read = nodes.get(&parent).unwrap();
-                let mut parent = node_read.write().unwrap();
-                let child = &mut parent.child;
-                let node = node.read().unwrap();
-                // Can't copy or pass a ref to Protobuf
-                // So we clone
-                child.push(node.clone());
-            }
+    #[tokio::test]
+    async fn decide_from_the_header_when_it_is_given() {
+        assert!(ftv1(ApolloTracingDataExt::default()).await.is_none());
+        assert!(ftv1(with_header("other")).await.is_none());
+
+        let forced = ApolloTracingDataExt {
+            include_federated_trace: true,
+            ..with_header("other")
         };
+        assert!(ftv1(forced).await.is_none());
+        let without_header = ApolloTracingDataExt {
+            include_federated_trace: true,
+            ..Default::default()
+        };
+        assert!(ftv1(without_header).await.is_some());
+    }
 
-        res
+    #[tokio::test]
+    async fn report_the_cache_policies() {
+        let report = report(vec![Request::new("query Cached { cached }")]).await;
+        let trace = trace(&report, "# Cached\nquery Cached{cached}");
+        let expected = CachePolicy {
+            scope: EnumOrUnknown::new(Scope::PUBLIC),
+            max_age_ns: 60_000_000_000,
+            ..Default::default()
+        };
+        assert_eq!(trace.cache_policy.as_ref(), Some(&expected));
+        assert_eq!(trace.root.child[0].cache_policy.as_ref(), Some(&expected));
+
+        let stats =
+            &report.traces_per_query["# Cached\nquery Cached{cached}"].stats_with_context[0];
+        assert!(!stats.query_latency_stats.public_cache_ttl_count.is_empty());
+        assert!(stats.query_latency_stats.private_cache_ttl_count.is_empty());
+    }
+
+    #[tokio::test]
+    async fn report_persisted_query_hits_and_registrations() {
+        let persisted = |query: &str| {
+            let mut request = Request::new(query);
+            let persisted_query = value!({ "version": 1, "sha256Hash": "abc" });
+            request
+                .extensions
+                .insert("persistedQuery".to_string(), persisted_query);
+            request
+        };
+        let report = report(vec![
+            persisted("query Value { value }"),
+            persisted(""),
+            persisted(""),
+            Request::new("query Value { value }"),
+        ])
+        .await;
+
+        let operation = &report.traces_per_query["# Value\nquery Value{value}"];
+        let flags: Vec<_> = operation
+            .trace
+            .iter()
+            .map(|trace| (trace.persisted_query_register, trace.persisted_query_hit))
+            .collect();
+        assert_eq!(
+            flags,
+            vec![(true, false), (false, true), (false, true), (false, false)]
+        );
+        let stats = &operation.stats_with_context[0].query_latency_stats;
+        assert_eq!(stats.persisted_query_misses, 1);
+        assert_eq!(stats.persisted_query_hits, 2);
+        assert_eq!(operation.query_metadata.pq_id, "abc");
+        assert_eq!(operation.query_metadata.name, "Value");
+        assert_eq!(operation.query_metadata.signature, "query Value{value}");
+    }
+
+    #[tokio::test]
+    async fn report_the_operation_types() {
+        let report = report(vec![
+            Request::new("query Value { value }"),
+            Request::new("mutation Set { set(value: 1) }"),
+            Request::new("query Value { value }"),
+            Request::new("query Broken {"),
+        ])
+        .await;
+
+        let operation_type = |key: &str| trace(&report, key).operation_type.clone();
+        assert_eq!(
+            report.traces_per_query["# Value\nquery Value{value}"].trace[0].operation_type,
+            "query"
+        );
+        assert_eq!(
+            operation_type("# Set\nmutation Set{set(value:0)}"),
+            "mutation"
+        );
+        assert_eq!(operation_type(PARSE_FAILURE_KEY), "");
+
+        let mut counts: Vec<_> = report
+            .operation_count_by_type
+            .iter()
+            .map(|count| {
+                let type_ = (count.type_.as_str(), count.subtype.as_str());
+                (type_, count.operation_count)
+            })
+            .collect();
+        counts.sort();
+        assert_eq!(
+            counts,
+            vec![(("", ""), 1), (("mutation", ""), 1), (("query", ""), 2)]
+        );
+        assert_eq!(report.operation_count, 4);
+    }
+
+    #[tokio::test]
+    async fn report_subscription_requests_and_events() {
+        let (schema, tracing, sink) = schema();
+        let events: Vec<_> = schema
+            .execute_stream(Request::new("subscription Values { values(count: 3) }"))
+            .collect()
+            .await;
+        assert_eq!(events.len(), 3);
+        let failed: Vec<_> = schema
+            .execute_stream(Request::new("subscription Invalid { unknown }"))
+            .collect()
+            .await;
+        assert_eq!(failed.len(), 1);
+        let report = sent_report(tracing, sink).await;
+
+        let traces =
+            &report.traces_per_query["# Values\nsubscription Values{values(count:0)}"].trace;
+        let subtypes: Vec<_> = traces
+            .iter()
+            .map(|trace| {
+                (
+                    trace.operation_type.as_str(),
+                    trace.operation_subtype.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            subtypes,
+            vec![
+                ("subscription", SUBSCRIPTION_REQUEST),
+                ("subscription", SUBSCRIPTION_EVENT),
+                ("subscription", SUBSCRIPTION_EVENT),
+                ("subscription", SUBSCRIPTION_EVENT),
+            ]
+        );
+        assert!(traces[0].root.child.is_empty());
+        assert_eq!(traces[0].field_execution_weight, 1.);
+        for event in &traces[1..] {
+            assert_eq!(event.root.child[0].response_name(), "values");
+        }
+
+        let invalid = trace(&report, VALIDATION_FAILURE_KEY);
+        assert_eq!(invalid.unexecutedOperationName, "");
+        assert_eq!(
+            invalid.unexecutedOperationBody,
+            "subscription Invalid { unknown }"
+        );
+        assert_eq!(report.operation_count, 5);
     }
 }
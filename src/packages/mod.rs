@@ -1,3 +1,2 @@
-
 pub mod serde_json;
-pub mod uname;
\ No newline at end of file
+pub mod uname;
@@ -1,4 +1,3 @@
-
 #[cfg(not(target_arch = "wasm32"))]
 pub use serde_json::*;
 #[cfg(target_arch = "wasm32")]
@@ -1,4 +1,3 @@
-
 cfg_if::cfg_if! {
     if #[cfg(any(target_arch = "wasm32", target_os = "windows"))] {
         pub fn uname() -> std::io::Result<String> {
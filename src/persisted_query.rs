@@ -0,0 +1,92 @@
+//! # Automatic persisted queries
+//!
+//! Clients using [automatic persisted queries](https://www.apollographql.com/docs/apollo-server/performance/apq/)
+//! send the SHA-256 hash of their query in the `persistedQuery` extension of the request, along
+//! with the query itself the first time to register it. Apollo Studio reports how often the hash
+//! alone was enough.
+//!
+//! async_graphql's `ApolloPersistedQueries` removes the extension from the request, so the
+//! [ApolloTracing](crate::ApolloTracing) extension has to be registered before it to see it.
+use async_graphql::{Request, Value};
+
+/// Persisted query used by a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistedQuery {
+    /// The SHA-256 hash of the query.
+    pub id: String,
+    /// Whether the query was sent along with its hash to register it, a miss for Apollo Studio.
+    pub register: bool,
+}
+
+impl PersistedQuery {
+    /// Read the `persistedQuery` extension of the request, before it's resolved.
+    pub fn from_request(request: &Request) -> Option<Self> {
+        let Some(Value::Object(persisted_query)) = request.extensions.get("persistedQuery") else {
+            return None;
+        };
+        let Some(Value::String(id)) = persisted_query.get("sha256Hash") else {
+            return None;
+        };
+        Some(Self {
+            id: id.clone(),
+            register: !request.query.is_empty(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{value, Name};
+
+    use super::*;
+
+    fn request(query: &str, persisted_query: Value) -> Request {
+        let mut request = Request::new(query);
+        request
+            .extensions
+            .insert("persistedQuery".to_string(), persisted_query);
+        request
+    }
+
+    #[test]
+    fn hash_alone_is_a_hit() {
+        let persisted_query = value!({ "version": 1, "sha256Hash": "abc" });
+        assert_eq!(
+            PersistedQuery::from_request(&request("", persisted_query)),
+            Some(PersistedQuery {
+                id: "abc".to_string(),
+                register: false,
+            })
+        );
+    }
+
+    #[test]
+    fn hash_with_its_query_registers_it() {
+        let persisted_query = value!({ "version": 1, "sha256Hash": "abc" });
+        assert_eq!(
+            PersistedQuery::from_request(&request("{ value }", persisted_query)),
+            Some(PersistedQuery {
+                id: "abc".to_string(),
+                register: true,
+            })
+        );
+    }
+
+    #[test]
+    fn ignore_requests_without_hash() {
+        assert_eq!(
+            PersistedQuery::from_request(&Request::new("{ value }")),
+            None
+        );
+        assert_eq!(
+            PersistedQuery::from_request(&request("", value!({ "version": 1 }))),
+            None
+        );
+        assert_eq!(
+            PersistedQuery::from_request(&request("", value!({ "sha256Hash": 1 }))),
+            None
+        );
+        let hash = Value::Enum(Name::new("abc"));
+        assert_eq!(PersistedQuery::from_request(&request("", hash)), None);
+    }
+}
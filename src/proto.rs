@@ -4,7 +4,6 @@
 #[allow(unknown_lints)]
 #[allow(unused_attributes)]
 #[cfg_attr(rustfmt, rustfmt::skip)]
-#[allow(box_pointers)]
 #[allow(dead_code)]
 #[allow(missing_docs)]
 #[allow(non_camel_case_types)]
@@ -0,0 +1,231 @@
+//! # Referenced fields
+//!
+//! Apollo Studio tracks the usage of every field referenced by an operation, even the ones which
+//! weren't executed, for instance because their parent was `null`. They are computed from the
+//! operation document and the schema, once per operation signature.
+//!
+//! Port of Apollo Server's `calculateReferencedFieldsByType`:
+//! <https://github.com/apollographql/apollo-server/blob/main/packages/usage-reporting-protobuf/src/referencedFields.ts>
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use async_graphql::parser::types::{ExecutableDocument, OperationType, Selection, SelectionSet};
+use async_graphql::registry::{MetaType, Registry};
+
+use crate::proto::reports::ReferencedFieldsForType;
+use crate::signature::find_operation;
+
+/// Compute the fields referenced by the executed operation, grouped by the type they are
+/// selected on.
+///
+/// The operation is selected like [usage_reporting_key](crate::signature::usage_reporting_key)
+/// does, an empty map is returned when no operation would be executed.
+pub fn referenced_fields_by_type(
+    registry: &Registry,
+    document: &ExecutableDocument,
+    operation_name: Option<&str>,
+) -> HashMap<String, ReferencedFieldsForType> {
+    let Some((_, operation)) = find_operation(&document.operations, operation_name) else {
+        return HashMap::new();
+    };
+
+    let root_type = match operation.ty {
+        OperationType::Query => Some(registry.query_type.as_str()),
+        OperationType::Mutation => registry.mutation_type.as_deref(),
+        OperationType::Subscription => registry.subscription_type.as_deref(),
+    };
+    let Some(root_type) = root_type.and_then(|name| registry.types.get(name)) else {
+        return HashMap::new();
+    };
+
+    let mut collector = Collector {
+        registry,
+        document,
+        visited_fragments: HashSet::new(),
+        fields_by_type: HashMap::new(),
+    };
+    collector.selection_set(&operation.selection_set.node, root_type);
+
+    collector
+        .fields_by_type
+        .into_iter()
+        .map(|(type_name, (field_names, is_interface))| {
+            let referenced = ReferencedFieldsForType {
+                field_names: field_names.into_iter().map(str::to_string).collect(),
+                is_interface,
+                special_fields: Default::default(),
+            };
+            (type_name.to_string(), referenced)
+        })
+        .collect()
+}
+
+struct Collector<'a> {
+    registry: &'a Registry,
+    document: &'a ExecutableDocument,
+    /// Like Apollo Server, a fragment is only visited once whatever the number of spreads.
+    visited_fragments: HashSet<&'a str>,
+    /// Field names by parent type, and whether the parent type is an interface.
+    fields_by_type: HashMap<&'a str, (BTreeSet<&'a str>, bool)>,
+}
+
+impl<'a> Collector<'a> {
+    fn selection_set(&mut self, selection_set: &'a SelectionSet, parent_type: &'a MetaType) {
+        for selection in &selection_set.items {
+            match &selection.node {
+                Selection::Field(field) => {
+                    let field_name = field.node.name.node.as_str();
+                    let (fields, _) = self
+                        .fields_by_type
+                        .entry(parent_type.name())
+                        .or_insert_with(|| {
+                            (
+                                BTreeSet::new(),
+                                matches!(parent_type, MetaType::Interface { .. }),
+                            )
+                        });
+                    fields.insert(field_name);
+
+                    // Introspection fields and leaves don't have a type to go through.
+                    let field_type = parent_type
+                        .field_by_name(field_name)
+                        .and_then(|field| self.registry.concrete_type_by_name(&field.ty));
+                    if let Some(field_type) = field_type {
+                        self.selection_set(&field.node.selection_set.node, field_type);
+                    }
+                }
+                Selection::InlineFragment(fragment) => {
+                    let fragment_type = match &fragment.node.type_condition {
+                        Some(condition) => self.registry.types.get(condition.node.on.node.as_str()),
+                        None => Some(parent_type),
+                    };
+                    if let Some(fragment_type) = fragment_type {
+                        self.selection_set(&fragment.node.selection_set.node, fragment_type);
+                    }
+                }
+                Selection::FragmentSpread(spread) => {
+                    let name = spread.node.fragment_name.node.as_str();
+                    if !self.visited_fragments.insert(name) {
+                        continue;
+                    }
+                    let Some(fragment) = self.document.fragments.get(name) else {
+                        continue;
+                    };
+                    let condition = fragment.node.type_condition.node.on.node.as_str();
+                    if let Some(fragment_type) = self.registry.types.get(condition) {
+                        self.selection_set(&fragment.node.selection_set.node, fragment_type);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::parser::parse_query;
+    use async_graphql::{Interface, Object, OutputType, SimpleObject};
+
+    use super::*;
+
+    #[derive(SimpleObject)]
+    struct User {
+        id: i32,
+        name: String,
+    }
+
+    #[derive(Interface)]
+    #[graphql(field(name = "id", ty = "&i32"))]
+    enum Node {
+        User(User),
+    }
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn user(&self) -> User {
+            User {
+                id: 1,
+                name: "ada".to_string(),
+            }
+        }
+
+        async fn node(&self) -> Node {
+            Node::User(User {
+                id: 1,
+                name: "ada".to_string(),
+            })
+        }
+    }
+
+    struct Mutation;
+
+    #[Object]
+    impl Mutation {
+        async fn set(&self, value: i32) -> i32 {
+            value
+        }
+    }
+
+    /// Referenced fields of the operation, sorted by type, with `*` after interfaces.
+    fn referenced(query: &str, operation_name: Option<&str>) -> Vec<String> {
+        let mut registry = Registry::default();
+        Query::create_type_info(&mut registry);
+        Mutation::create_type_info(&mut registry);
+        registry.query_type = "Query".to_string();
+        registry.mutation_type = Some("Mutation".to_string());
+        let document = parse_query(query).unwrap();
+
+        let mut referenced: Vec<_> =
+            referenced_fields_by_type(&registry, &document, operation_name)
+                .into_iter()
+                .map(|(type_name, fields)| {
+                    let interface = if fields.is_interface { "*" } else { "" };
+                    format!("{type_name}{interface}: {}", fields.field_names.join(" "))
+                })
+                .collect();
+        referenced.sort();
+        referenced
+    }
+
+    #[test]
+    fn nested_fields() {
+        assert_eq!(
+            referenced("{ user { name id } __typename }", None),
+            vec!["Query: __typename user", "User: id name"]
+        );
+    }
+
+    #[test]
+    fn fields_of_fragments() {
+        let query = "
+            query Op { ...Root ...Root user { ...Name } }
+            fragment Root on Query { user { id } }
+            fragment Name on User { name }
+        ";
+        assert_eq!(
+            referenced(query, None),
+            vec!["Query: user", "User: id name"]
+        );
+    }
+
+    #[test]
+    fn fields_of_interfaces() {
+        assert_eq!(
+            referenced("{ node { id ... on User { name } } }", None),
+            vec!["Node*: id", "Query: node", "User: name"]
+        );
+    }
+
+    #[test]
+    fn fields_of_the_selected_operation() {
+        let query = "query A { user { id } } mutation B { set(value: 1) }";
+        assert_eq!(
+            referenced(query, Some("A")),
+            vec!["Query: user", "User: id"]
+        );
+        assert_eq!(referenced(query, Some("B")), vec!["Mutation: set"]);
+        assert!(referenced(query, Some("C")).is_empty());
+        assert!(referenced(query, None).is_empty());
+    }
+}
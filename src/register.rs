@@ -7,7 +7,8 @@ use reqwest::Client;
 use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-const SCHEMA_URL: &str = "https://schema-reporting.api.apollographql.com/api/graphql";
+/// Apollo Studio endpoint receiving the schemas.
+pub const DEFAULT_SCHEMA_URL: &str = "https://schema-reporting.api.apollographql.com/api/graphql";
 const TARGET_LOG: &str = "apollo-studio-extension-register";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const RUNTIME_VERSION: &str = "Rust - No runtime version provided yet";
@@ -48,7 +49,6 @@ pub fn sha_dynamic(schema: &dynamic::Schema) -> String {
 /// * `variant` - The name of the graph variant to register the schema to. The default value is current.
 /// * `user_version` - An arbitrary string you can set to distinguish data sent by different versions of your edge server. For example, this can be the SHA of the Git commit for your deployed server code. We plan to make this value visible in Apollo Studio.
 /// * `platform` - The infrastructure environment that your edge server is running in (localhost, kubernetes/deployment, aws lambda, google cloud run, google cloud function, AWS ECS, etc.)
-#[instrument(err, skip(authorization_token, schema))]
 pub async fn register<
     Q: ObjectType + 'static,
     M: ObjectType + 'static,
@@ -60,6 +60,35 @@ pub async fn register<
     variant: &str,
     user_version: &str,
     platform: &str,
+) -> anyhow::Result<()> {
+    register_with_endpoint(
+        DEFAULT_SCHEMA_URL,
+        authorization_token,
+        schema,
+        server_id,
+        variant,
+        user_version,
+        platform,
+    )
+    .await
+}
+
+/// Same as [register], sending the schema to `endpoint` instead of [DEFAULT_SCHEMA_URL], see
+/// [ApolloTracingConfigBuilder::endpoints](crate::ApolloTracingConfigBuilder::endpoints) for the
+/// reporting counterpart.
+#[instrument(err, skip(authorization_token, schema))]
+pub async fn register_with_endpoint<
+    Q: ObjectType + 'static,
+    M: ObjectType + 'static,
+    S: SubscriptionType + 'static,
+>(
+    endpoint: &str,
+    authorization_token: &str,
+    schema: &Schema<Q, M, S>,
+    server_id: &str,
+    variant: &str,
+    user_version: &str,
+    platform: &str,
 ) -> anyhow::Result<()> {
     info!(
         target: TARGET_LOG,
@@ -111,13 +140,15 @@ pub async fn register<
     );
 
     let result = client
-        .post(SCHEMA_URL)
-        .body(format!(r#"{{
+        .post(endpoint)
+        .body(format!(
+            r#"{{
             \"query\": {mutation},
             \"variables\": {{
                 \"schema\": {schema_sdl},
             }},
-        }}"#))
+        }}"#
+        ))
         .header("content-type", "application/json")
         .header("X-Api-Key", authorization_token)
         .send()
@@ -150,7 +181,6 @@ pub async fn register<
 /// * `variant` - The name of the graph variant to register the schema to. The default value is current.
 /// * `user_version` - An arbitrary string you can set to distinguish data sent by different versions of your edge server. For example, this can be the SHA of the Git commit for your deployed server code. We plan to make this value visible in Apollo Studio.
 /// * `platform` - The infrastructure environment that your edge server is running in (localhost, kubernetes/deployment, aws lambda, google cloud run, google cloud function, AWS ECS, etc.)
-#[instrument(err, skip(authorization_token, schema))]
 pub async fn register_dynamic(
     authorization_token: &str,
     schema: &dynamic::Schema,
@@ -158,6 +188,30 @@ pub async fn register_dynamic(
     variant: &str,
     user_version: &str,
     platform: &str,
+) -> anyhow::Result<()> {
+    register_dynamic_with_endpoint(
+        DEFAULT_SCHEMA_URL,
+        authorization_token,
+        schema,
+        server_id,
+        variant,
+        user_version,
+        platform,
+    )
+    .await
+}
+
+/// Same as [register_dynamic], sending the schema to `endpoint` instead of [DEFAULT_SCHEMA_URL],
+/// like [register_with_endpoint].
+#[instrument(err, skip(authorization_token, schema))]
+pub async fn register_dynamic_with_endpoint(
+    endpoint: &str,
+    authorization_token: &str,
+    schema: &dynamic::Schema,
+    server_id: &str,
+    variant: &str,
+    user_version: &str,
+    platform: &str,
 ) -> anyhow::Result<()> {
     info!(
         target: TARGET_LOG,
@@ -209,13 +263,15 @@ pub async fn register_dynamic(
     );
 
     let result = client
-        .post(SCHEMA_URL)
-        .body(format!(r#"{{
+        .post(endpoint)
+        .body(format!(
+            r#"{{
             \"query\": {mutation},
             \"variables\": {{
                 \"schema\": {schema_sdl},
             }},
-        }}"#))
+        }}"#
+        ))
         .header("content-type", "application/json")
         .header("X-Api-Key", authorization_token)
         .send()
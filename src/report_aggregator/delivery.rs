@@ -0,0 +1,229 @@
+//! Delivery of the reports to their [ReportSink].
+use std::{sync::Arc, time::Duration};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
+
+use crate::proto::reports::Report;
+use crate::runtime;
+
+use super::retry::{CircuitBreaker, CircuitBreakerPolicy, RetryPolicy};
+use super::sink::{DeliveryError, ReportSink};
+use super::spool::SharedSpool;
+use super::TARGET_LOG;
+
+/// Messages handled by the delivery task, in order.
+pub enum DeliveryMessage {
+    Report {
+        report: Report,
+        count: usize,
+    },
+    /// Notified once the reports queued before it are sent.
+    Done(oneshot::Sender<()>),
+}
+
+/// Send reports to a [ReportSink], retrying failed deliveries and stopping after repeated fatal
+/// failures. Reports which can't be sent, because they still fail after their retries, were
+/// rejected or the circuit is open, are spooled when there is a spool.
+pub struct Delivery {
+    sink: Arc<dyn ReportSink>,
+    send_timeout: Duration,
+    retry: RetryPolicy,
+    circuit_breaker: CircuitBreaker,
+    spool: Option<SharedSpool>,
+}
+
+impl Delivery {
+    pub fn new(
+        sink: Arc<dyn ReportSink>,
+        send_timeout: Duration,
+        retry: RetryPolicy,
+        circuit_breaker: CircuitBreakerPolicy,
+    ) -> Self {
+        Self {
+            sink,
+            send_timeout,
+            retry,
+            circuit_breaker: CircuitBreaker::new(circuit_breaker),
+            spool: None,
+        }
+    }
+
+    pub fn with_spool(mut self, spool: Option<SharedSpool>) -> Self {
+        self.spool = spool;
+        self
+    }
+
+    /// Send the queued reports one after the other, until every sender of the queue is dropped.
+    pub async fn run(mut self, mut queue: mpsc::Receiver<DeliveryMessage>) {
+        while let Some(message) = queue.next().await {
+            match message {
+                DeliveryMessage::Report { report, count } => self.send(report, count).await,
+                DeliveryMessage::Done(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, report: Report, count: usize) {
+        use tracing::{span, Level};
+
+        let span_batch = span!(
+            Level::DEBUG,
+            "Sending traces by batch to Apollo Studio",
+            batched = ?count,
+        );
+
+        span_batch.in_scope(|| {
+            trace!(target: TARGET_LOG, message = "Sending traces by batch");
+        });
+
+        if self.circuit_breaker.is_open() {
+            let err = "Too many fatal errors while sending reports".to_string();
+            self.give_up(report, err, 0).await;
+            return;
+        }
+
+        let mut retry = 0;
+        loop {
+            let result = runtime::timeout(self.send_timeout, self.sink.send(&report))
+                .await
+                .unwrap_or_else(|| {
+                    Err(DeliveryError::Retryable(format!(
+                        "Timed out after {:?}",
+                        self.send_timeout
+                    )))
+                });
+            match result {
+                Ok(()) => {
+                    self.circuit_breaker.record_success();
+                    return;
+                }
+                Err(DeliveryError::Retryable(err)) if retry < self.retry.max_retries => {
+                    let backoff = self.retry.backoff(retry);
+                    warn!(target: TARGET_LOG, message = "Retrying to send the report", error = ?err, ?backoff);
+                    runtime::sleep(backoff).await;
+                    retry += 1;
+                }
+                Err(DeliveryError::Retryable(err)) => {
+                    self.give_up(report, err, retry).await;
+                    return;
+                }
+                Err(DeliveryError::Fatal(err)) => {
+                    self.circuit_breaker.record_fatal_failure();
+                    // The rejection may come from a proxy or a revoked key about to be replaced.
+                    self.give_up(report, err, retry).await;
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn give_up(&self, report: Report, err: String, retries: u32) {
+        let Some(spool) = &self.spool else {
+            error!(target: TARGET_LOG, message = "Couldn't send the report, dropping it", error = ?err, ?retries);
+            return;
+        };
+        warn!(target: TARGET_LOG, message = "Couldn't send the report, spooling it", error = ?err, ?retries);
+        if let Err(err) = spool.push(report).await {
+            error!(target: TARGET_LOG, message = "Couldn't spool the report, dropping it", error = ?err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::retry::CircuitBreakerPolicy;
+    use super::super::sink::tests::TestSink;
+    use super::super::spool::tests::policy;
+    use super::*;
+
+    fn report(operation_count: u64) -> Report {
+        Report {
+            operation_count,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn spool_reports_while_the_circuit_is_open() {
+        let spool = SharedSpool::new(policy("circuit-open"));
+        let sink = Arc::new(TestSink::new(|_| {
+            Err(DeliveryError::Fatal("rejected".to_string()))
+        }));
+        let circuit_breaker = CircuitBreakerPolicy {
+            failure_threshold: 1,
+            open_duration: Duration::from_secs(60),
+        };
+        let mut delivery = Delivery::new(
+            sink.clone(),
+            Duration::from_secs(1),
+            RetryPolicy::default(),
+            circuit_breaker,
+        )
+        .with_spool(Some(spool.clone()));
+
+        delivery.send(report(1), 1).await;
+        delivery.send(report(2), 1).await;
+        // The first report opened the circuit, the second one isn't even attempted.
+        assert_eq!(sink.attempts(), vec![1]);
+        let spooled: Vec<_> = spool
+            .reports()
+            .await
+            .iter()
+            .map(|report| report.operation_count)
+            .collect();
+        assert_eq!(spooled, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn retry_until_the_report_is_sent() {
+        let failures = std::sync::atomic::AtomicU32::new(0);
+        let sink = Arc::new(TestSink::new(move |_| {
+            if failures.fetch_add(1, std::sync::atomic::Ordering::Relaxed) < 2 {
+                Err(DeliveryError::Retryable("unavailable".to_string()))
+            } else {
+                Ok(())
+            }
+        }));
+        let retry = RetryPolicy {
+            max_retries: 4,
+            min_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let mut delivery = Delivery::new(
+            sink.clone(),
+            Duration::from_secs(1),
+            retry,
+            CircuitBreakerPolicy::default(),
+        );
+
+        delivery.send(report(1), 1).await;
+        assert_eq!(sink.attempts(), vec![1, 1, 1]);
+        assert_eq!(sink.sent(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn drop_reports_without_spool() {
+        let sink = Arc::new(TestSink::new(|_| {
+            Err(DeliveryError::Retryable("unavailable".to_string()))
+        }));
+        let retry = RetryPolicy {
+            max_retries: 2,
+            min_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let mut delivery = Delivery::new(
+            sink.clone(),
+            Duration::from_secs(1),
+            retry,
+            CircuitBreakerPolicy::default(),
+        );
+
+        delivery.send(report(1), 1).await;
+        assert_eq!(sink.attempts(), vec![1, 1, 1]);
+    }
+}
@@ -0,0 +1,126 @@
+//! Latency histogram using the bucketing scheme expected by Apollo Studio.
+//!
+//! Port of Apollo Server's `DurationHistogram`:
+//! <https://github.com/apollographql/apollo-server/blob/main/packages/server/src/plugin/usageReporting/durationHistogram.ts>
+
+/// Histogram of durations with 384 logarithmic buckets: the bucket of a duration of `x`
+/// microseconds is `max(0, min(ceil(ln(x) / ln(1.1)), 383))`.
+#[derive(Debug, Clone)]
+pub struct DurationHistogram {
+    buckets: Vec<f64>,
+}
+
+const BUCKET_COUNT: usize = 384;
+/// Most durations end up in the first buckets, avoid growing the array for them.
+const INITIAL_SIZE: usize = 74;
+
+impl Default for DurationHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0.; INITIAL_SIZE],
+        }
+    }
+}
+
+impl DurationHistogram {
+    fn bucket(duration_ns: u64) -> usize {
+        let log = (duration_ns as f64 / 1000.).ln();
+        let bucket = (log / 1.1_f64.ln()).ceil();
+        // Compare <= 0 to catch -0 and -infinity
+        if bucket.is_nan() || bucket <= 0. {
+            0
+        } else if bucket >= BUCKET_COUNT as f64 {
+            BUCKET_COUNT - 1
+        } else {
+            bucket as usize
+        }
+    }
+
+    /// Count a duration `weight` times.
+    pub fn increment_duration(&mut self, duration_ns: u64, weight: f64) {
+        let bucket = Self::bucket(duration_ns);
+        if bucket >= self.buckets.len() {
+            self.buckets.resize(bucket + 1, 0.);
+        }
+        self.buckets[bucket] += weight;
+    }
+
+    /// Encode the histogram the way the protobuf expects it: a run of empty buckets is replaced
+    /// by its negated length, `0` for a single one, and trailing empty buckets are dropped.
+    pub fn into_proto(self) -> Vec<i64> {
+        let mut buffered_zeroes: i64 = 0;
+        let mut result = Vec::new();
+
+        for value in self.buckets {
+            if value == 0. {
+                buffered_zeroes += 1;
+                continue;
+            }
+
+            match buffered_zeroes {
+                0 => {}
+                1 => result.push(0),
+                _ => result.push(-buffered_zeroes),
+            }
+            result.push(value.floor() as i64);
+            buffered_zeroes = 0;
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Duration in the middle of `bucket`, far from the float rounding at its bounds.
+    fn duration_ns(bucket: usize) -> u64 {
+        (1000. * 1.1_f64.powf(bucket as f64 - 0.5)) as u64
+    }
+
+    #[test]
+    fn bucket_boundaries() {
+        assert_eq!(DurationHistogram::bucket(0), 0);
+        assert_eq!(DurationHistogram::bucket(500), 0);
+        assert_eq!(DurationHistogram::bucket(1_000), 0);
+        assert_eq!(DurationHistogram::bucket(1_001), 1);
+        assert_eq!(DurationHistogram::bucket(2_000), 8);
+        // One second.
+        assert_eq!(DurationHistogram::bucket(1_000_000_000), 145);
+        assert_eq!(DurationHistogram::bucket(u64::MAX), BUCKET_COUNT - 1);
+        for bucket in 1..BUCKET_COUNT {
+            assert_eq!(DurationHistogram::bucket(duration_ns(bucket)), bucket);
+        }
+    }
+
+    #[test]
+    fn empty_histogram() {
+        assert!(DurationHistogram::default().into_proto().is_empty());
+    }
+
+    #[test]
+    fn encode_runs_of_empty_buckets() {
+        let mut histogram = DurationHistogram::default();
+        histogram.increment_duration(duration_ns(100), 1.);
+        histogram.increment_duration(duration_ns(102), 1.);
+        histogram.increment_duration(duration_ns(102), 1.);
+        assert_eq!(histogram.into_proto(), vec![-100, 1, 0, 2]);
+
+        let mut histogram = DurationHistogram::default();
+        histogram.increment_duration(duration_ns(0), 3.);
+        histogram.increment_duration(duration_ns(1), 1.);
+        histogram.increment_duration(duration_ns(3), 1.);
+        histogram.increment_duration(duration_ns(6), 1.);
+        assert_eq!(histogram.into_proto(), vec![3, 1, 0, 1, -2, 1]);
+    }
+
+    #[test]
+    fn round_weights_down() {
+        let mut histogram = DurationHistogram::default();
+        histogram.increment_duration(duration_ns(1), 0.5);
+        histogram.increment_duration(duration_ns(1), 0.75);
+        histogram.increment_duration(duration_ns(383), 2.5);
+        assert_eq!(histogram.into_proto(), vec![0, 1, -381, 2]);
+    }
+}
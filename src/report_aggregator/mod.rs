@@ -1,150 +1,529 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
+use chrono::Utc;
 use futures::{
-    channel::mpsc::{self, Sender},
-    StreamExt,
+    channel::{
+        mpsc::{self, Sender},
+        oneshot,
+    },
+    SinkExt, StreamExt,
 };
-use protobuf::Message;
+use protobuf::{well_known_types::timestamp::Timestamp, MessageField};
 
 use crate::{
-    proto::reports::{Report, ReportHeader, Trace, TracesAndStats},
+    config::ApolloTracingConfig,
     packages::uname,
-    runtime::{abort, spawn, Instant, JoinHandle},
+    proto::reports::{
+        report::OperationCountByType, QueryMetadata, ReferencedFieldsForType, Report, ReportHeader,
+        Trace, TracesAndStats,
+    },
+    runtime::{self, abort, spawn, JoinHandle},
 };
 
+mod delivery;
+mod duration_histogram;
+mod retry;
+mod sink;
+mod spool;
+mod stats;
+use delivery::{Delivery, DeliveryMessage};
+use spool::SharedSpool;
+use stats::OperationStats;
+
+pub use retry::{CircuitBreakerPolicy, RetryPolicy};
+pub use sink::{ApolloHttpSink, DeliveryError, ReportSink};
+pub use spool::SpoolPolicy;
+
+/// An operation execution sent by the extension to the aggregator.
+pub struct TracedOperation {
+    /// Key used to group operations inside a [Report], the operation signature.
+    pub key: String,
+    pub trace: Trace,
+    /// When `false`, the trace only contributes to the stats and isn't sent to Apollo Studio.
+    pub keep_trace: bool,
+    /// Fields referenced by the operation, the same for every operation with this key.
+    pub referenced_fields_by_type: HashMap<String, ReferencedFieldsForType>,
+    /// Hash of the automatic persisted query used to request the operation.
+    pub persisted_query_id: Option<String>,
+}
+
+/// Messages handled by the background task of the [ReportAggregator].
+pub enum AggregatorMessage {
+    Trace(Box<TracedOperation>),
+    /// Send the pending traces right away, `done` is notified once the report is sent.
+    Flush {
+        done: oneshot::Sender<()>,
+    },
+    /// Stop accepting traces, send everything left in the channel and stop the task.
+    Shutdown {
+        done: oneshot::Sender<()>,
+    },
+}
+
+/// Everything collected for one operation key until the next [Report] is sent.
+#[derive(Default)]
+struct PendingOperation {
+    traces: Vec<Trace>,
+    stats: OperationStats,
+    referenced_fields_by_type: HashMap<String, ReferencedFieldsForType>,
+    query_metadata: Option<QueryMetadata>,
+}
+
+impl PendingOperation {
+    fn add(&mut self, operation: TracedOperation) {
+        let TracedOperation {
+            key,
+            trace,
+            keep_trace,
+            referenced_fields_by_type,
+            persisted_query_id,
+        } = operation;
+
+        self.stats.add_trace(&trace);
+        if keep_trace {
+            self.traces.push(trace);
+        }
+        // Only stored once per key as it only depends on the operation signature.
+        if self.referenced_fields_by_type.is_empty() {
+            self.referenced_fields_by_type = referenced_fields_by_type;
+        }
+        if self.query_metadata.is_none() {
+            self.query_metadata = persisted_query_id.and_then(|pq_id| query_metadata(&key, pq_id));
+        }
+    }
+
+    fn into_traces_and_stats(self) -> TracesAndStats {
+        TracesAndStats {
+            trace: self.traces,
+            stats_with_context: self.stats.into_proto(),
+            referenced_fields_by_type: self.referenced_fields_by_type,
+            query_metadata: self.query_metadata.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Everything collected until the next [Report] is sent.
+struct PendingReport {
+    operations: HashMap<String, PendingOperation>,
+    /// Number of operations by type and subtype, including the ones only sent as stats.
+    operation_count_by_type: HashMap<(String, String), u64>,
+}
+
+impl PendingReport {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            operations: HashMap::with_capacity(capacity),
+            operation_count_by_type: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, operation: TracedOperation) {
+        let operation_type = (
+            operation.trace.operation_type.clone(),
+            operation.trace.operation_subtype.clone(),
+        );
+        *self
+            .operation_count_by_type
+            .entry(operation_type)
+            .or_default() += 1;
+        self.operations
+            .entry(operation.key.clone())
+            .or_default()
+            .add(operation);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
+
+/// Metadata of an operation requested with a persisted query, from its `# name\nsignature` key.
+/// Operations which couldn't be executed don't have a signature, they have no metadata.
+fn query_metadata(key: &str, pq_id: String) -> Option<QueryMetadata> {
+    let (name, signature) = key.strip_prefix("# ")?.split_once('\n')?;
+    Some(QueryMetadata {
+        name: if name == "-" { "" } else { name }.to_string(),
+        signature: signature.to_string(),
+        pq_id,
+        special_fields: Default::default(),
+    })
+}
+
 /// The [ReportAggregator] is the structure which control the background task spawned to aggregate
 /// and send data through Apollo Studio by constructing [Report] ready to be send
+///
+/// Reports are delivered by another task, so retries don't keep traces from being aggregated.
+/// When deliveries can't keep up, traces and reports are dropped instead of piling up in memory.
+///
+/// When the last [ReportAggregator] is dropped, the background task sends what is left once
+/// every in-flight trace is received, if the runtime gives it the chance to. The spool replays
+/// stop along with the deliveries.
 pub struct ReportAggregator {
-    #[allow(dead_code)]
     handle: JoinHandle<()>,
-    sender: Sender<(String, Trace)>,
+    delivery_handle: JoinHandle<()>,
+    /// Task replaying the spooled reports, when there is a spool.
+    replay_handle: Option<JoinHandle<()>>,
+    /// Only this sender is used for traces: every clone of a sender gets its own slot in the
+    /// channel, which wouldn't be bounded anymore.
+    sender: Mutex<Sender<AggregatorMessage>>,
+    /// Traces dropped because the channel was full, since the last report.
+    dropped_traces: Arc<AtomicU64>,
 }
 
-const REPORTING_URL: &str = "https://usage-reporting.api.apollographql.com/api/ingress/traces";
+/// Apollo Studio ingress receiving the reports.
+pub const DEFAULT_REPORTING_URL: &str =
+    "https://usage-reporting.api.apollographql.com/api/ingress/traces";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const TARGET_LOG: &str = "apollo-studio-extension";
-const BUFFER_SLOTS: usize = 32;
-const MAX_TRACES: usize = 64;
+/// Number of reports waiting to be delivered, the next ones are dropped while it's full.
+const DELIVERY_QUEUE_CAPACITY: usize = 4;
 
 impl ReportAggregator {
-    pub fn initialize(
-        authorization_token: String,
-        hostname: String,
-        graph_id: String,
-        variant: String,
-        service_version: String,
-    ) -> Self {
-        let (tx, mut rx) = mpsc::channel::<(String, Trace)>(BUFFER_SLOTS);
+    pub fn initialize(config: ApolloTracingConfig) -> Self {
+        let (tx, mut rx) = mpsc::channel::<AggregatorMessage>(config.channel_capacity);
 
+        let (graph_id, variant) = config.graph_id_and_variant();
         let reported_header = ReportHeader {
-            uname: uname::uname()
-                .ok()
-                .unwrap_or_else(|| "No uname provided".to_string()),
-            hostname,
+            uname: config.uname.clone().unwrap_or_else(|| {
+                uname::uname()
+                    .ok()
+                    .unwrap_or_else(|| "No uname provided".to_string())
+            }),
             graph_ref: format!("{graph_id}@{variant}"),
-            service_version,
-            agent_version: format!("async-studio-extension-{}", VERSION),
-            runtime_version: "Rust".to_string(),
-            executable_schema_id: graph_id,
+            executable_schema_id: config
+                .executable_schema_id
+                .clone()
+                .unwrap_or_else(|| graph_id.to_string()),
+            hostname: config.hostname.clone(),
+            service_version: config.service_version.clone(),
+            agent_version: config
+                .agent_version
+                .clone()
+                .unwrap_or_else(|| format!("async-studio-extension-{}", VERSION)),
+            runtime_version: config
+                .runtime_version
+                .clone()
+                .unwrap_or_else(|| "Rust".to_string()),
             special_fields: Default::default(),
         };
 
-        let handle = spawn(async move {
-            let client = reqwest::Client::new();
+        let ApolloTracingConfig {
+            authorization_token,
+            max_traces,
+            flush_interval,
+            send_timeout,
+            retry,
+            circuit_breaker,
+            endpoints,
+            sink,
+            spool,
+            ..
+        } = config;
+        // The default sink times out on each endpoint, the deadline covers all of them.
+        let (sink, send_timeout): (Arc<dyn ReportSink>, _) = match sink {
+            Some(sink) => (sink, send_timeout),
+            None => {
+                let sink = ApolloHttpSink::new(authorization_token)
+                    .with_endpoints(endpoints)
+                    .with_timeout(send_timeout);
+                let deadline = sink.max_send_duration();
+                (Arc::new(sink), deadline)
+            }
+        };
 
-            let mut hashmap: HashMap<String, TracesAndStats> = HashMap::with_capacity(MAX_TRACES);
+        let spool = spool.map(SharedSpool::new);
+        let delivery = Delivery::new(sink.clone(), send_timeout, retry, circuit_breaker.clone())
+            .with_spool(spool.clone());
+        // Dropped once the deliveries are done, which stops the replays too.
+        let (delivery_done, stopped) = oneshot::channel::<()>();
+        let replay_handle = spool.map(|spool| {
+            spawn(spool::replay(
+                spool,
+                sink,
+                send_timeout,
+                circuit_breaker,
+                stopped,
+            ))
+        });
+
+        let (mut delivery_tx, delivery_rx) = mpsc::channel(DELIVERY_QUEUE_CAPACITY);
+        let delivery_handle = spawn(async move {
+            delivery.run(delivery_rx).await;
+            drop(delivery_done);
+        });
+
+        let dropped_traces = Arc::new(AtomicU64::new(0));
+        let dropped = dropped_traces.clone();
+        let handle = spawn(async move {
+            let mut pending = PendingReport::with_capacity(max_traces);
 
             let mut count = 0;
-            let mut now = Instant::now();
+            let mut shutdown_done = None;
+
+            // Reports are sent on schedule even when no trace is coming.
+            let ticks = runtime::interval(flush_interval).fuse();
+            futures::pin_mut!(ticks);
 
-            while let Some((name, trace)) = rx.next().await {
-                trace!(target: TARGET_LOG, message = "Trace registered", trace = ?trace, name = ?name);
-                match hashmap.get_mut(&name) {
-                    Some(previous) => {
-                        previous.trace.push(trace);
+            loop {
+                // `None` when it's time to send a report.
+                let message = futures::select! {
+                    message = rx.next() => match message {
+                        Some(message) => Some(message),
+                        None => break,
+                    },
+                    _ = ticks.next() => None,
+                };
+
+                let done = match message {
+                    None => None,
+                    Some(AggregatorMessage::Trace(operation)) => {
+                        let operation = *operation;
+                        trace!(target: TARGET_LOG, message = "Trace registered", trace = ?operation.trace, name = ?operation.key);
+                        pending.add(operation);
+                        count += 1;
+
+                        if count < max_traces {
+                            continue;
+                        }
+                        None
                     }
-                    None => {
-                        let mut trace_and_stats = TracesAndStats::default();
-                        trace_and_stats.trace.push(trace);
-                        hashmap.insert(name, trace_and_stats);
+                    Some(AggregatorMessage::Flush { done }) => Some(done),
+                    Some(AggregatorMessage::Shutdown { done }) => {
+                        // Traces already in the channel are still received.
+                        rx.close();
+                        shutdown_done = Some(done);
+                        continue;
                     }
+                };
+
+                let dropped = dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    warn!(target: TARGET_LOG, message = "Traces dropped, the aggregator couldn't keep up", dropped);
                 }
 
-                count += 1;
+                if !pending.is_empty() {
+                    let to_send =
+                        std::mem::replace(&mut pending, PendingReport::with_capacity(max_traces));
+                    let report = DeliveryMessage::Report {
+                        report: build_report(&reported_header, to_send),
+                        count,
+                    };
+                    if let Err(err) = delivery_tx.try_send(report) {
+                        if err.is_full() {
+                            error!(target: TARGET_LOG, message = "Too many reports waiting to be sent, dropping the report", batched = ?count);
+                        }
+                    }
+                }
+                count = 0;
 
-                if count > MAX_TRACES || now.elapsed() > Duration::from_secs(5) {
-                    now = Instant::now();
-                    use tracing::{field, field::debug, span, Level};
+                // Waits for room in the queue, flushes are rare and have their own timeout.
+                if let Some(done) = done {
+                    let _ = delivery_tx.send(DeliveryMessage::Done(done)).await;
+                }
+            }
 
-                    let span_batch = span!(
-                        Level::DEBUG,
-                        "Sending traces by batch to Apollo Studio",
-                        response = field::Empty,
-                        batched = ?count,
-                    );
+            if !pending.is_empty() {
+                let report = DeliveryMessage::Report {
+                    report: build_report(&reported_header, pending),
+                    count,
+                };
+                let _ = delivery_tx.send(report).await;
+            }
+            if let Some(done) = shutdown_done {
+                let _ = delivery_tx.send(DeliveryMessage::Done(done)).await;
+            }
+        });
 
-                    span_batch.in_scope(|| {
-                        trace!(target: TARGET_LOG, message = "Sending traces by batch");
-                    });
+        Self {
+            handle,
+            delivery_handle,
+            replay_handle,
+            sender: Mutex::new(tx),
+            dropped_traces,
+        }
+    }
 
-                    let hashmap_to_send = hashmap;
-                    hashmap = HashMap::with_capacity(MAX_TRACES);
+    /// Send an operation to the aggregator without waiting, it's dropped when the aggregator
+    /// can't keep up.
+    pub fn send_trace(&self, operation: TracedOperation) {
+        let result = self
+            .sender
+            .lock()
+            .unwrap()
+            .try_send(AggregatorMessage::Trace(Box::new(operation)));
+        if let Err(err) = result {
+            if err.is_full() {
+                self.dropped_traces.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
 
-                    let report: Report = Report {
-                        traces_pre_aggregated: false,
-                        traces_per_query: hashmap_to_send,
-                        header: Some(reported_header.clone()).into(),
-                        ..Default::default()
-                    };
+    /// Send the pending traces, waiting at most `timeout` for the report to be sent.
+    pub async fn flush(&self, timeout: Duration) -> anyhow::Result<()> {
+        let (done, receiver) = oneshot::channel();
+        self.notify(AggregatorMessage::Flush { done }, receiver, timeout)
+            .await
+    }
 
-                    let msg = report.write_to_bytes().unwrap();
+    /// Send everything left and stop the background task, waiting at most `timeout` for it. The
+    /// task is aborted if it didn't finish in time.
+    pub async fn shutdown(&self, timeout: Duration) -> anyhow::Result<()> {
+        let (done, receiver) = oneshot::channel();
+        let result = self
+            .notify(AggregatorMessage::Shutdown { done }, receiver, timeout)
+            .await;
+        abort(&self.handle);
+        abort(&self.delivery_handle);
+        if let Some(replay_handle) = &self.replay_handle {
+            abort(replay_handle);
+        }
+        result
+    }
 
-                    let mut client = client
-                        .post(REPORTING_URL)
-                        .header("content-type", "application/protobuf")
-                        .header("accept", "application/json")
-                        .header("X-Api-Key", &authorization_token);
+    async fn notify(
+        &self,
+        message: AggregatorMessage,
+        receiver: oneshot::Receiver<()>,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        let mut sender = self.sender.lock().unwrap().clone();
+        let notified = async move {
+            sender.send(message).await?;
+            receiver.await?;
+            Ok(())
+        };
 
-                    if cfg!(feature = "compression") {
-                        client = client.header("content-encoding", "gzip");
-                    };
+        match runtime::timeout(timeout, notified).await {
+            Some(result) => result,
+            None => Err(anyhow::anyhow!("Reports weren't sent within {timeout:?}")),
+        }
+    }
+}
 
-                    let msg = match crate::compression::compress(msg) {
-                        Ok(result) => result,
-                        Err(e) => {
-                            error!(target: TARGET_LOG, message = "An issue happened while GZIP compression", err = ?e);
-                            continue;
-                        }
-                    };
+/// Build a [Report] from the pending operations.
+fn build_report(reported_header: &ReportHeader, pending: PendingReport) -> Report {
+    let end_time = Utc::now();
+    let PendingReport {
+        operations,
+        operation_count_by_type,
+    } = pending;
 
-                    let result = client.body(msg).send().await;
+    // Every operation is described in the stats, traces are only a sampling of them.
+    Report {
+        traces_pre_aggregated: true,
+        traces_per_query: operations
+            .into_iter()
+            .map(|(key, pending)| (key, pending.into_traces_and_stats()))
+            .collect(),
+        header: Some(reported_header.clone()).into(),
+        operation_count: operation_count_by_type.values().sum(),
+        operation_count_by_type: operation_count_by_type
+            .into_iter()
+            .map(|((type_, subtype), operation_count)| OperationCountByType {
+                type_,
+                subtype,
+                operation_count,
+                special_fields: Default::default(),
+            })
+            .collect(),
+        // Required when there is no trace, with the stats-only mode.
+        end_time: MessageField::some(Timestamp {
+            seconds: end_time.timestamp(),
+            nanos: end_time.timestamp_subsec_nanos() as i32,
+            special_fields: Default::default(),
+        }),
+        ..Default::default()
+    }
+}
 
-                    match result {
-                        Ok(data) => {
-                            span_batch.record("response", &debug(&data));
-                            let text = data.text().await;
-                            info!(target: TARGET_LOG, data = ?text);
-                        }
-                        Err(err) => {
-                            let status_code = err.status();
-                            error!(target: TARGET_LOG, status = ?status_code, error = ?err);
-                        }
-                    }
-                }
-            }
-        });
+#[cfg(test)]
+mod tests {
+    use super::sink::tests::TestSink;
+    use super::*;
 
-        Self { handle, sender: tx }
+    fn operation() -> TracedOperation {
+        TracedOperation {
+            key: "# Op\nquery Op{field}".to_string(),
+            trace: Trace::default(),
+            keep_trace: true,
+            referenced_fields_by_type: HashMap::new(),
+            persisted_query_id: None,
+        }
     }
 
-    pub fn sender(&self) -> Sender<(String, Trace)> {
-        self.sender.clone()
+    #[tokio::test]
+    async fn send_a_report_once_max_traces_are_aggregated() {
+        let sink = Arc::new(TestSink::new(|_| Ok(())));
+        let mut config = ApolloTracingConfig::builder()
+            .authorization_token("token")
+            .graph_ref("test@current")
+            .max_traces(3_usize)
+            .flush_interval(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        config.sink = Some(sink.clone());
+        let aggregator = ReportAggregator::initialize(config);
+
+        for _ in 0..2 {
+            aggregator.send_trace(operation());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sink.attempts().is_empty());
+
+        for _ in 0..3 {
+            aggregator.send_trace(operation());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(sink.sent(), vec![3]);
+
+        aggregator.shutdown(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(sink.sent(), vec![3, 2]);
     }
-}
 
-impl Drop for ReportAggregator {
-    fn drop(&mut self) {
-        abort(&self.handle);
-        // TODO: Wait for the proper aborted task
+    #[test]
+    fn count_the_operations_sent_as_stats_only() {
+        let mut pending = PendingReport::with_capacity(2);
+        for keep_trace in [true, false] {
+            let mut operation = operation();
+            operation.trace.operation_type = "query".to_string();
+            operation.keep_trace = keep_trace;
+            pending.add(operation);
+        }
+        let mut subscription = operation();
+        subscription.trace.operation_type = "subscription".to_string();
+        subscription.trace.operation_subtype = "subscription-request".to_string();
+        subscription.keep_trace = false;
+        pending.add(subscription);
+
+        let report = build_report(&ReportHeader::default(), pending);
+        assert_eq!(report.operation_count, 3);
+        let mut counts: Vec<_> = report
+            .operation_count_by_type
+            .iter()
+            .map(|count| {
+                let type_ = (count.type_.as_str(), count.subtype.as_str());
+                (type_, count.operation_count)
+            })
+            .collect();
+        counts.sort();
+        assert_eq!(
+            counts,
+            vec![
+                (("query", ""), 2),
+                (("subscription", "subscription-request"), 1)
+            ]
+        );
+        assert_eq!(
+            report.traces_per_query["# Op\nquery Op{field}"].trace.len(),
+            1
+        );
     }
 }
@@ -0,0 +1,194 @@
+//! Retries and circuit breaking for report deliveries.
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// How failed deliveries are retried.
+///
+/// Only retryable failures are retried: server errors, timeouts and connection errors. The delay
+/// between two attempts doubles each time, with some jitter, from `min_backoff` up to
+/// `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt, the report is dropped past it. Defaults to 4.
+    pub max_retries: u32,
+    /// Delay before the first retry. Defaults to 100ms.
+    pub min_backoff: Duration,
+    /// Maximum delay between two attempts. Defaults to 10s.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the retry number `retry`, starting at 0.
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        self.backoff_with_jitter(retry, fastrand::f64())
+    }
+
+    /// Delay before the retry number `retry`, with a `jitter` between 0 and 1.
+    fn backoff_with_jitter(&self, retry: u32, jitter: f64) -> Duration {
+        let backoff = self
+            .min_backoff
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        // Jitter between half and the full backoff, so instances don't retry all at once.
+        backoff.mul_f64(0.5 + jitter / 2.)
+    }
+}
+
+/// When to stop sending reports after fatal failures.
+///
+/// Fatal failures, like an invalid API key, won't go away by retrying. After
+/// `failure_threshold` of them in a row, reports are dropped without being sent for
+/// `open_duration`.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerPolicy {
+    /// Number of consecutive fatal failures opening the circuit. Defaults to 3.
+    pub failure_threshold: u32,
+    /// How long reports are dropped once the circuit is open. Defaults to 1 minute.
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            open_duration: Duration::from_secs(60),
+        }
+    }
+}
+
+pub(crate) struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    consecutive_failures: u32,
+    open_until: Option<DateTime<Utc>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: CircuitBreakerPolicy) -> Self {
+        Self {
+            policy,
+            consecutive_failures: 0,
+            open_until: None,
+        }
+    }
+
+    /// `true` while deliveries shouldn't be attempted.
+    pub fn is_open(&mut self) -> bool {
+        match self.open_until {
+            Some(open_until) if Utc::now() < open_until => true,
+            Some(_) => {
+                // Half-open: let the next delivery go through, one more fatal failure opens the
+                // circuit again.
+                self.open_until = None;
+                self.consecutive_failures = self.policy.failure_threshold.saturating_sub(1);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+    }
+
+    pub fn record_fatal_failure(&mut self) {
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= self.policy.failure_threshold {
+            let open_until = chrono::Duration::from_std(self.policy.open_duration)
+                .ok()
+                .and_then(|duration| Utc::now().checked_add_signed(duration))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC);
+            self.open_until = Some(open_until);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(backoff: Duration) -> u128 {
+        backoff.as_millis()
+    }
+
+    #[test]
+    fn double_the_backoff_up_to_the_maximum() {
+        let retry = RetryPolicy::default();
+        let backoffs: Vec<_> = (0..10)
+            .map(|n| millis(retry.backoff_with_jitter(n, 1.)))
+            .collect();
+        assert_eq!(
+            backoffs,
+            vec![100, 200, 400, 800, 1600, 3200, 6400, 10000, 10000, 10000]
+        );
+        assert_eq!(millis(retry.backoff_with_jitter(u32::MAX, 1.)), 10000);
+    }
+
+    #[test]
+    fn jitter_between_half_and_the_full_backoff() {
+        let retry = RetryPolicy::default();
+        assert_eq!(millis(retry.backoff_with_jitter(1, 0.)), 100);
+        assert_eq!(millis(retry.backoff_with_jitter(1, 0.5)), 150);
+        assert_eq!(millis(retry.backoff_with_jitter(1, 1.)), 200);
+
+        for _ in 0..100 {
+            let backoff = retry.backoff(1);
+            assert!(backoff >= Duration::from_millis(100));
+            assert!(backoff <= Duration::from_millis(200));
+        }
+    }
+
+    fn circuit_breaker(open_duration: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 3,
+            open_duration,
+        })
+    }
+
+    #[test]
+    fn open_after_consecutive_fatal_failures() {
+        let mut circuit_breaker = circuit_breaker(Duration::from_secs(60));
+        circuit_breaker.record_fatal_failure();
+        circuit_breaker.record_fatal_failure();
+        circuit_breaker.record_success();
+        circuit_breaker.record_fatal_failure();
+        circuit_breaker.record_fatal_failure();
+        assert!(!circuit_breaker.is_open());
+
+        circuit_breaker.record_fatal_failure();
+        assert!(circuit_breaker.is_open());
+        assert!(circuit_breaker.is_open());
+    }
+
+    #[test]
+    fn let_a_delivery_through_once_the_circuit_was_open_long_enough() {
+        let mut circuit_breaker = circuit_breaker(Duration::from_millis(20));
+        for _ in 0..3 {
+            circuit_breaker.record_fatal_failure();
+        }
+        assert!(circuit_breaker.is_open());
+        std::thread::sleep(Duration::from_millis(30));
+
+        // Half-open: a single fatal failure opens the circuit again.
+        assert!(!circuit_breaker.is_open());
+        circuit_breaker.record_fatal_failure();
+        assert!(circuit_breaker.is_open());
+        std::thread::sleep(Duration::from_millis(30));
+
+        // A success closes it.
+        assert!(!circuit_breaker.is_open());
+        circuit_breaker.record_success();
+        circuit_breaker.record_fatal_failure();
+        assert!(!circuit_breaker.is_open());
+    }
+}
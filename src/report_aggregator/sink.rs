@@ -0,0 +1,230 @@
+//! Destinations of the reports built by the aggregator.
+use std::time::Duration;
+
+use protobuf::Message;
+
+use crate::proto::reports::Report;
+use crate::runtime;
+
+use super::{DEFAULT_REPORTING_URL, TARGET_LOG};
+
+/// Why a report couldn't be sent, decides if it's worth retrying.
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// Server errors, timeouts, connection errors.
+    Retryable(String),
+    /// Errors which won't go away by retrying, like an invalid API key.
+    Fatal(String),
+}
+
+impl std::fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::Retryable(err) => write!(f, "retryable delivery error: {err}"),
+            DeliveryError::Fatal(err) => write!(f, "fatal delivery error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+/// Where the reports end up once built.
+///
+/// [ApolloHttpSink] sends them to Apollo Studio and is used by default. Implement this trait to
+/// write reports to a file, send them to an internal collector, capture them in tests or fan them
+/// out to several destinations.
+///
+/// Failed reports are retried following the [RetryPolicy](crate::RetryPolicy) when the error is
+/// [DeliveryError::Retryable], so `send` can be called several times with the same report.
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+pub trait ReportSink: Send + Sync {
+    async fn send(&self, report: &Report) -> Result<(), DeliveryError>;
+}
+
+/// Send the reports to the Apollo Studio ingress.
+///
+/// Several endpoints can be given, they are tried in order: the next one is only used when the
+/// previous one failed with a [DeliveryError::Retryable] error. Each endpoint has its own
+/// timeout, so an unreachable one doesn't keep the next ones from being tried.
+pub struct ApolloHttpSink {
+    client: reqwest::Client,
+    authorization_token: String,
+    endpoints: Vec<String>,
+    timeout: Duration,
+}
+
+/// How long connecting to an endpoint can take, unreachable hosts can hang much longer.
+#[cfg(not(target_arch = "wasm32"))]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl ApolloHttpSink {
+    /// Send the reports to [DEFAULT_REPORTING_URL](crate::DEFAULT_REPORTING_URL).
+    pub fn new(authorization_token: impl Into<String>) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        #[cfg(target_arch = "wasm32")]
+        let client = reqwest::Client::new();
+
+        Self {
+            client,
+            authorization_token: authorization_token.into(),
+            endpoints: vec![DEFAULT_REPORTING_URL.to_string()],
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Send the reports to these endpoints instead, by order of preference, see
+    /// [ApolloTracingConfigBuilder::endpoints](crate::ApolloTracingConfigBuilder::endpoints).
+    pub fn with_endpoints<I, E>(mut self, endpoints: I) -> Self
+    where
+        I: IntoIterator<Item = E>,
+        E: Into<String>,
+    {
+        self.endpoints = endpoints.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// How long sending a report to one endpoint can take before trying the next one. Defaults
+    /// to 30 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The longest a [send](ReportSink::send) can take, when every endpoint times out.
+    pub(crate) fn max_send_duration(&self) -> Duration {
+        let endpoints = u32::try_from(self.endpoints.len()).unwrap_or(u32::MAX);
+        self.timeout.saturating_mul(endpoints.max(1))
+    }
+
+    async fn send_to(&self, endpoint: &str, msg: &[u8]) -> Result<(), DeliveryError> {
+        let mut client = self
+            .client
+            .post(endpoint)
+            .header("content-type", "application/protobuf")
+            .header("accept", "application/json")
+            .header("X-Api-Key", &self.authorization_token);
+
+        if cfg!(feature = "compression") {
+            client = client.header("content-encoding", "gzip");
+        };
+
+        let response = client
+            .body(msg.to_vec())
+            .send()
+            .await
+            .map_err(|err| classify_error(&err))?;
+
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+
+        if status.is_success() {
+            info!(target: TARGET_LOG, data = ?text, endpoint);
+            Ok(())
+        } else if status.is_server_error()
+            || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || status == reqwest::StatusCode::REQUEST_TIMEOUT
+        {
+            Err(DeliveryError::Retryable(format!("{status}: {text}")))
+        } else {
+            Err(DeliveryError::Fatal(format!("{status}: {text}")))
+        }
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl ReportSink for ApolloHttpSink {
+    async fn send(&self, report: &Report) -> Result<(), DeliveryError> {
+        let msg = report
+            .write_to_bytes()
+            .map_err(|err| DeliveryError::Fatal(err.to_string()))?;
+        let msg = crate::compression::compress(msg).map_err(|err| {
+            DeliveryError::Fatal(format!("An issue happened while GZIP compression: {err}"))
+        })?;
+
+        let mut last_error = DeliveryError::Fatal("No reporting endpoint configured".to_string());
+        for endpoint in &self.endpoints {
+            let result = runtime::timeout(self.timeout, self.send_to(endpoint, &msg))
+                .await
+                .unwrap_or_else(|| {
+                    Err(DeliveryError::Retryable(format!(
+                        "Timed out after {:?}",
+                        self.timeout
+                    )))
+                });
+            match result {
+                Err(DeliveryError::Retryable(err)) => {
+                    warn!(target: TARGET_LOG, message = "Couldn't reach the reporting endpoint", endpoint, error = ?err);
+                    last_error = DeliveryError::Retryable(err);
+                }
+                result => return result,
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+fn classify_error(err: &reqwest::Error) -> DeliveryError {
+    if err.is_builder() || err.is_redirect() {
+        DeliveryError::Fatal(err.to_string())
+    } else {
+        // Timeouts, connection errors and other transport errors.
+        DeliveryError::Retryable(err.to_string())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    type Respond = dyn Fn(&Report) -> Result<(), DeliveryError> + Send + Sync;
+
+    /// Answers each report with `respond`, reports are told apart by their operation count.
+    pub struct TestSink {
+        respond: Box<Respond>,
+        /// Every report the sink was asked to send.
+        pub attempts: Mutex<Vec<u64>>,
+        /// The reports sent successfully.
+        pub sent: Mutex<Vec<u64>>,
+    }
+
+    impl TestSink {
+        pub fn new(
+            respond: impl Fn(&Report) -> Result<(), DeliveryError> + Send + Sync + 'static,
+        ) -> Self {
+            Self {
+                respond: Box::new(respond),
+                attempts: Mutex::new(Vec::new()),
+                sent: Mutex::new(Vec::new()),
+            }
+        }
+
+        pub fn attempts(&self) -> Vec<u64> {
+            self.attempts.lock().unwrap().clone()
+        }
+
+        pub fn sent(&self) -> Vec<u64> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ReportSink for TestSink {
+        async fn send(&self, report: &Report) -> Result<(), DeliveryError> {
+            self.attempts.lock().unwrap().push(report.operation_count);
+            let result = (self.respond)(report);
+            if result.is_ok() {
+                self.sent.lock().unwrap().push(report.operation_count);
+            }
+            result
+        }
+    }
+}
@@ -0,0 +1,693 @@
+//! Spooling to disk of the reports which couldn't be delivered, to send them again later.
+//!
+//! Reports are appended to files of the spool directory as length-delimited protobuf messages.
+//! Files are rotated once they reach `max_file_size`, and replayed oldest first by a background
+//! task, which stops at the first failure and tries again later. Files left by a previous process
+//! are picked up when the spool is opened.
+//!
+//! A rejected report is dropped so it doesn't hold back the ones behind it. Replays have their
+//! own circuit breaker: after repeated rejections they pause without tripping the one of the
+//! deliveries, so only a few reports are lost to a revoked key.
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+    StreamExt,
+};
+use protobuf::{CodedInputStream, Message};
+
+use crate::proto::reports::Report;
+use crate::runtime;
+
+use super::retry::{CircuitBreaker, CircuitBreakerPolicy};
+use super::sink::{DeliveryError, ReportSink};
+use super::TARGET_LOG;
+
+const EXTENSION: &str = "spool";
+
+/// Where and how long reports which couldn't be delivered are kept.
+///
+/// Reports which can't be sent, because they still fail after their retries, were rejected or
+/// too many deliveries failed, are written to `directory` instead of being dropped and sent
+/// again later. The spool survives restarts, but each process needs its own directory. Not
+/// available on WebAssembly.
+#[derive(Debug, Clone)]
+pub struct SpoolPolicy {
+    /// Directory of the spool files, created when missing.
+    pub directory: PathBuf,
+    /// Size of the spool, the oldest files are removed past it. Defaults to 64MiB.
+    pub max_size: u64,
+    /// Size of a file before a new one is started. Defaults to 4MiB.
+    pub max_file_size: u64,
+    /// Reports older than this are removed without being sent. Defaults to 24 hours.
+    pub max_age: Duration,
+    /// How often the spool is replayed. Defaults to 30 seconds.
+    pub replay_interval: Duration,
+}
+
+impl SpoolPolicy {
+    /// Spool the reports to `directory`, with the default limits.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            max_size: 64 * 1024 * 1024,
+            max_file_size: 4 * 1024 * 1024,
+            max_age: Duration::from_secs(24 * 60 * 60),
+            replay_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+struct SpoolFile {
+    path: PathBuf,
+    size: u64,
+}
+
+/// The files of the spool directory, only used through [SharedSpool] to keep the file system
+/// calls off the executor.
+struct Spool {
+    policy: SpoolPolicy,
+    /// Whether the directory was scanned for the files of a previous process.
+    opened: bool,
+    /// Files waiting to be replayed, oldest first.
+    closed: VecDeque<SpoolFile>,
+    /// File the reports are appended to.
+    current: Option<(SpoolFile, File)>,
+}
+
+impl Spool {
+    /// Create the spool directory and pick up the files left by a previous process, the first
+    /// time the spool is used.
+    fn open(&mut self) -> anyhow::Result<()> {
+        if self.opened {
+            return Ok(());
+        }
+        let directory = &self.policy.directory;
+        fs::create_dir_all(directory)
+            .with_context(|| format!("Couldn't create the spool directory {directory:?}"))?;
+
+        let mut closed = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == EXTENSION)
+            {
+                closed.push(SpoolFile {
+                    path,
+                    size: entry.metadata()?.len(),
+                });
+            }
+        }
+        // Names start with their creation time.
+        closed.sort_by(|a, b| a.path.cmp(&b.path));
+        self.closed.extend(closed);
+        self.opened = true;
+        self.remove_expired();
+        Ok(())
+    }
+
+    /// Append `report` to the spool, removing the oldest files when it's full.
+    fn push(&mut self, report: &Report) -> anyhow::Result<()> {
+        self.open()?;
+        let bytes = report.write_length_delimited_to_bytes()?;
+        let len = bytes.len() as u64;
+
+        if matches!(&self.current, Some((file, _)) if file.size + len > self.policy.max_file_size) {
+            self.rotate();
+        }
+        self.remove_expired();
+        while self.size() + len > self.policy.max_size {
+            let Some(oldest) = self.closed.pop_front() else {
+                anyhow::bail!("The report is larger than the spool");
+            };
+            warn!(target: TARGET_LOG, message = "Spool full, dropping its oldest reports", path = ?oldest.path);
+            remove(&oldest.path);
+        }
+
+        let (file, handle) = match &mut self.current {
+            Some(current) => current,
+            None => self.current.insert(self.create()?),
+        };
+        handle.write_all(&bytes)?;
+        file.size += len;
+        Ok(())
+    }
+
+    /// The oldest file to replay, the current one is rotated when it's the only one left.
+    fn oldest(&mut self) -> anyhow::Result<Option<PathBuf>> {
+        self.open()?;
+        self.remove_expired();
+        if self.closed.is_empty() {
+            self.rotate();
+        }
+        Ok(self.closed.front().map(|file| file.path.clone()))
+    }
+
+    /// Replace the reports of the file at `path` by the ones not sent yet, or remove it when
+    /// there are none. The file may already have been removed to make room.
+    fn replayed(&mut self, path: &Path, remaining: &[Report]) -> anyhow::Result<()> {
+        let Some(index) = self.closed.iter().position(|file| file.path == path) else {
+            return Ok(());
+        };
+        if remaining.is_empty() {
+            self.closed.remove(index);
+            remove(path);
+            return Ok(());
+        }
+
+        let mut bytes = Vec::new();
+        for report in remaining {
+            report.write_length_delimited_to_vec(&mut bytes)?;
+        }
+        fs::write(path, &bytes)?;
+        self.closed[index].size = bytes.len() as u64;
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        let current = self.current.as_ref().map_or(0, |(file, _)| file.size);
+        self.closed.iter().map(|file| file.size).sum::<u64>() + current
+    }
+
+    fn create(&self) -> anyhow::Result<(SpoolFile, File)> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // Files are ordered by name, several can be created within the same millisecond.
+        let mut sequence = 0;
+        loop {
+            let path = self
+                .policy
+                .directory
+                .join(format!("{now:020}-{sequence:04}.{EXTENSION}"));
+            match OpenOptions::new().append(true).create_new(true).open(&path) {
+                Ok(handle) => return Ok((SpoolFile { path, size: 0 }, handle)),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => sequence += 1,
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Couldn't create the spool file {path:?}"))
+                }
+            }
+        }
+    }
+
+    fn rotate(&mut self) {
+        if let Some((file, _)) = self.current.take() {
+            if file.size > 0 {
+                self.closed.push_back(file);
+            } else {
+                remove(&file.path);
+            }
+        }
+    }
+
+    /// Remove the files which weren't written to since `max_age`.
+    fn remove_expired(&mut self) {
+        let max_age = self.policy.max_age;
+        self.closed.retain(|file| {
+            let expired = fs::metadata(&file.path)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > max_age));
+            if expired {
+                warn!(target: TARGET_LOG, message = "Dropping expired spooled reports", path = ?file.path);
+                remove(&file.path);
+            }
+            !expired
+        });
+    }
+}
+
+/// The spool shared by the deliveries and the replays, its file system calls run on a thread
+/// where blocking is fine.
+#[derive(Clone)]
+pub(crate) struct SharedSpool(Arc<Mutex<Spool>>);
+
+impl SharedSpool {
+    /// The directory is only opened when the spool is first used.
+    pub fn new(policy: SpoolPolicy) -> Self {
+        Self(Arc::new(Mutex::new(Spool {
+            policy,
+            opened: false,
+            closed: VecDeque::new(),
+            current: None,
+        })))
+    }
+
+    fn policy(&self) -> SpoolPolicy {
+        self.0.lock().unwrap().policy.clone()
+    }
+
+    async fn with<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Spool) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let spool = self.0.clone();
+        runtime::spawn_blocking(move || f(&mut spool.lock().unwrap())).await?
+    }
+
+    /// Append `report` to the spool, removing the oldest files when it's full.
+    pub async fn push(&self, report: Report) -> anyhow::Result<()> {
+        self.with(move |spool| spool.push(&report)).await
+    }
+
+    async fn oldest(&self) -> anyhow::Result<Option<PathBuf>> {
+        self.with(Spool::oldest).await
+    }
+
+    async fn replayed(&self, path: PathBuf, remaining: Vec<Report>) -> anyhow::Result<()> {
+        self.with(move |spool| spool.replayed(&path, &remaining))
+            .await
+    }
+}
+
+#[cfg(test)]
+impl SharedSpool {
+    /// Every spooled report, oldest first.
+    pub async fn reports(&self) -> Vec<Report> {
+        self.with(|spool| {
+            spool.rotate();
+            let mut reports = Vec::new();
+            for file in &spool.closed {
+                reports.extend(read(&file.path)?);
+            }
+            Ok(reports)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+fn remove(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            error!(target: TARGET_LOG, message = "Couldn't remove the spool file", path = ?path, error = ?err);
+        }
+    }
+}
+
+/// Read the reports of a spool file. A report cut short by a crash ends the file.
+fn read(path: &Path) -> anyhow::Result<Vec<Report>> {
+    let bytes = fs::read(path)?;
+    let mut input = CodedInputStream::from_bytes(&bytes);
+    let mut reports = Vec::new();
+    while !input.eof()? {
+        // `read_message` would decode a cut report as a shorter one, read its exact bytes first.
+        let report = input
+            .read_bytes()
+            .and_then(|bytes| Report::parse_from_bytes(&bytes));
+        match report {
+            Ok(report) => reports.push(report),
+            Err(err) => {
+                warn!(target: TARGET_LOG, message = "Truncated spool file", path = ?path, error = ?err);
+                break;
+            }
+        }
+    }
+    Ok(reports)
+}
+
+/// Whether the report ended more than `max_age` ago.
+fn is_expired(report: &Report, max_age: Duration) -> bool {
+    let end_time =
+        UNIX_EPOCH + Duration::from_secs(report.end_time.seconds.try_into().unwrap_or(0));
+    end_time.elapsed().is_ok_and(|age| age > max_age)
+}
+
+/// Send the spooled reports every `replay_interval`, in order, until the spool is empty or a
+/// delivery fails.
+///
+/// Replays stop with the deliveries, once `stopped` is notified or its sender dropped: the spool
+/// is left to the next process.
+pub(crate) async fn replay(
+    spool: SharedSpool,
+    sink: Arc<dyn ReportSink>,
+    send_timeout: Duration,
+    circuit_breaker: CircuitBreakerPolicy,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    // Rejected spooled reports don't keep the live reports from being sent.
+    let mut circuit_breaker = CircuitBreaker::new(circuit_breaker);
+    let SpoolPolicy {
+        replay_interval,
+        max_age,
+        ..
+    } = spool.policy();
+    let ticks = runtime::interval(replay_interval);
+    futures::pin_mut!(ticks);
+
+    loop {
+        // `select!` would skip `stopped` once its sender is dropped, taking it as terminated.
+        if let Either::Right(_) = future::select(ticks.next(), &mut stopped).await {
+            return;
+        }
+        loop {
+            if !matches!(stopped.try_recv(), Ok(None)) {
+                return;
+            }
+            if circuit_breaker.is_open() {
+                break;
+            }
+            let path = match spool.oldest().await {
+                Ok(Some(path)) => path,
+                Ok(None) => break,
+                Err(err) => {
+                    error!(target: TARGET_LOG, message = "Couldn't open the spool", error = ?err);
+                    break;
+                }
+            };
+            let read_path = path.clone();
+            let mut reports = match runtime::spawn_blocking(move || read(&read_path))
+                .await
+                .and_then(|reports| reports)
+            {
+                Ok(reports) => reports,
+                Err(err) => {
+                    error!(target: TARGET_LOG, message = "Couldn't read the spool file, dropping it", path = ?path, error = ?err);
+                    Vec::new()
+                }
+            };
+            let count = reports.len();
+            reports.retain(|report| !is_expired(report, max_age));
+            let expired = count - reports.len();
+
+            // Reports sent or rejected, they're removed from the file.
+            let mut replayed = 0;
+            let mut failed = false;
+            for report in &reports {
+                if circuit_breaker.is_open() {
+                    failed = true;
+                    break;
+                }
+                let result = runtime::timeout(send_timeout, sink.send(report))
+                    .await
+                    .unwrap_or_else(|| {
+                        Err(DeliveryError::Retryable(format!(
+                            "Timed out after {send_timeout:?}"
+                        )))
+                    });
+                match result {
+                    Ok(()) => {
+                        circuit_breaker.record_success();
+                        replayed += 1;
+                    }
+                    Err(DeliveryError::Retryable(err)) => {
+                        debug!(target: TARGET_LOG, message = "Couldn't replay the spool yet", error = ?err);
+                        failed = true;
+                        break;
+                    }
+                    Err(DeliveryError::Fatal(err)) => {
+                        error!(target: TARGET_LOG, message = "Spooled report rejected, dropping it", error = ?err);
+                        circuit_breaker.record_fatal_failure();
+                        replayed += 1;
+                    }
+                }
+            }
+
+            // Rewriting the file would reset its age, it's left alone when nothing changed.
+            if replayed > 0 || expired > 0 || reports.is_empty() {
+                reports.drain(..replayed);
+                if let Err(err) = spool.replayed(path.clone(), reports).await {
+                    error!(target: TARGET_LOG, message = "Couldn't update the spool file", path = ?path, error = ?err);
+                }
+            }
+            if failed {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use protobuf::{well_known_types::timestamp::Timestamp, MessageField};
+
+    use super::super::sink::tests::TestSink;
+    use super::*;
+
+    /// A policy for an empty directory, named after the test.
+    pub fn policy(test: &str) -> SpoolPolicy {
+        let directory =
+            std::env::temp_dir().join(format!("apollo-spool-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        SpoolPolicy::new(directory)
+    }
+
+    fn open(policy: SpoolPolicy) -> Spool {
+        let mut spool = Spool {
+            policy,
+            opened: false,
+            closed: VecDeque::new(),
+            current: None,
+        };
+        spool.open().unwrap();
+        spool
+    }
+
+    /// A report which just ended, told apart by its operation count.
+    fn report(operation_count: u64) -> Report {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        Report {
+            operation_count,
+            end_time: MessageField::some(Timestamp {
+                seconds: now.as_secs() as i64,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn counts(reports: &[Report]) -> Vec<u64> {
+        reports
+            .iter()
+            .map(|report| report.operation_count)
+            .collect()
+    }
+
+    /// Size of a spooled report, all the reports of these tests have the same.
+    fn len() -> u64 {
+        report(1).write_length_delimited_to_bytes().unwrap().len() as u64
+    }
+
+    #[test]
+    fn rotate_files() {
+        let mut policy = policy("rotate");
+        policy.max_file_size = 2 * len();
+        let mut spool = open(policy.clone());
+        for count in 1..=5 {
+            spool.push(&report(count)).unwrap();
+        }
+        assert_eq!(spool.closed.len(), 2);
+
+        // The current file is rotated when it's the only one left.
+        let mut replayed = Vec::new();
+        while let Some(path) = spool.oldest().unwrap() {
+            let reports = read(&path).unwrap();
+            replayed.extend(counts(&reports));
+            spool.replayed(&path, &[]).unwrap();
+        }
+        assert_eq!(replayed, vec![1, 2, 3, 4, 5]);
+        assert_eq!(fs::read_dir(&policy.directory).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn reopen_files_of_previous_process() {
+        let policy = policy("reopen");
+        let mut spool = open(policy.clone());
+        spool.push(&report(1)).unwrap();
+        spool.push(&report(2)).unwrap();
+        drop(spool);
+
+        let mut spool = open(policy);
+        let path = spool.oldest().unwrap().unwrap();
+        assert_eq!(counts(&read(&path).unwrap()), vec![1, 2]);
+    }
+
+    #[test]
+    fn keep_remaining_reports() {
+        let policy = policy("remaining");
+        let mut spool = open(policy);
+        for count in 1..=3 {
+            spool.push(&report(count)).unwrap();
+        }
+        let path = spool.oldest().unwrap().unwrap();
+        spool.replayed(&path, &[report(3)]).unwrap();
+        assert_eq!(spool.oldest().unwrap(), Some(path.clone()));
+        assert_eq!(counts(&read(&path).unwrap()), vec![3]);
+    }
+
+    #[test]
+    fn recover_truncated_file() {
+        let policy = policy("truncated");
+        let mut spool = open(policy);
+        spool.push(&report(1)).unwrap();
+        spool.push(&report(2)).unwrap();
+        let path = spool.oldest().unwrap().unwrap();
+
+        // Cut the last report short, like a crash while writing it.
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 2]).unwrap();
+        assert_eq!(counts(&read(&path).unwrap()), vec![1]);
+    }
+
+    #[test]
+    fn evict_oldest_files_when_full() {
+        let mut policy = policy("full");
+        policy.max_file_size = len();
+        policy.max_size = 3 * len();
+        let mut spool = open(policy);
+        for count in 1..=5 {
+            spool.push(&report(count)).unwrap();
+        }
+        assert!(spool.size() <= 3 * len());
+
+        let mut replayed = Vec::new();
+        while let Some(path) = spool.oldest().unwrap() {
+            replayed.extend(counts(&read(&path).unwrap()));
+            spool.replayed(&path, &[]).unwrap();
+        }
+        assert_eq!(replayed, vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn reject_report_larger_than_spool() {
+        let mut policy = policy("larger");
+        policy.max_size = len() - 1;
+        policy.max_file_size = len() - 1;
+        let mut spool = open(policy);
+        assert!(spool.push(&report(1)).is_err());
+    }
+
+    #[test]
+    fn evict_expired_files() {
+        let mut policy = policy("expired");
+        policy.max_file_size = len();
+        policy.max_age = Duration::from_secs(60);
+        let mut spool = open(policy);
+        spool.push(&report(1)).unwrap();
+        spool.push(&report(2)).unwrap();
+
+        let old = SystemTime::now() - Duration::from_secs(120);
+        let path = spool.oldest().unwrap().unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let remaining = spool.oldest().unwrap().unwrap();
+        assert_ne!(remaining, path);
+        assert!(!path.exists());
+        assert_eq!(counts(&read(&remaining).unwrap()), vec![2]);
+    }
+
+    #[test]
+    fn expired_reports() {
+        let max_age = Duration::from_secs(60);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let ended = |ago: u64| Report {
+            end_time: MessageField::some(Timestamp {
+                seconds: (now.as_secs() - ago) as i64,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(!is_expired(&ended(10), max_age));
+        assert!(is_expired(&ended(120), max_age));
+    }
+
+    #[tokio::test]
+    async fn stop_replays_with_the_deliveries() {
+        let mut policy = policy("stopped");
+        policy.replay_interval = Duration::from_millis(10);
+        let spool = SharedSpool::new(policy);
+        spool.push(report(1)).await.unwrap();
+        let sink = Arc::new(TestSink::new(|_| Ok(())));
+
+        let (delivery_done, stopped) = oneshot::channel();
+        drop(delivery_done);
+        let replay = replay(
+            spool,
+            sink.clone(),
+            Duration::from_secs(1),
+            CircuitBreakerPolicy::default(),
+            stopped,
+        );
+        assert!(runtime::timeout(Duration::from_secs(1), replay)
+            .await
+            .is_some());
+        assert!(sink.attempts().is_empty());
+    }
+
+    /// Replay `spool` until it's had the time to go through all of it.
+    async fn replay_for_a_while(
+        spool: SharedSpool,
+        sink: Arc<TestSink>,
+        circuit_breaker: CircuitBreakerPolicy,
+    ) {
+        let (delivery_done, stopped) = oneshot::channel();
+        let replay = tokio::spawn(replay(
+            spool,
+            sink,
+            Duration::from_secs(1),
+            circuit_breaker,
+            stopped,
+        ));
+        runtime::sleep(Duration::from_millis(200)).await;
+        drop(delivery_done);
+        replay.await.unwrap();
+    }
+
+    async fn spooled(spool: &SharedSpool) -> Vec<u64> {
+        counts(&spool.reports().await)
+    }
+
+    #[tokio::test]
+    async fn drop_rejected_reports() {
+        let mut policy = policy("rejected");
+        policy.replay_interval = Duration::from_millis(10);
+        let spool = SharedSpool::new(policy);
+        for count in 1..=3 {
+            spool.push(report(count)).await.unwrap();
+        }
+        let sink = Arc::new(TestSink::new(|report| match report.operation_count {
+            2 => Err(DeliveryError::Fatal("rejected".to_string())),
+            _ => Ok(()),
+        }));
+
+        replay_for_a_while(spool.clone(), sink.clone(), CircuitBreakerPolicy::default()).await;
+        assert_eq!(sink.sent(), vec![1, 3]);
+        assert!(spooled(&spool).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pause_replays_after_repeated_rejections() {
+        let mut policy = policy("breaker");
+        policy.replay_interval = Duration::from_millis(10);
+        let spool = SharedSpool::new(policy);
+        for count in 1..=4 {
+            spool.push(report(count)).await.unwrap();
+        }
+        let sink = Arc::new(TestSink::new(|_| {
+            Err(DeliveryError::Fatal("rejected".to_string()))
+        }));
+        let circuit_breaker = CircuitBreakerPolicy {
+            failure_threshold: 2,
+            open_duration: Duration::from_secs(60),
+        };
+
+        replay_for_a_while(spool.clone(), sink.clone(), circuit_breaker).await;
+        assert_eq!(sink.attempts(), vec![1, 2]);
+        assert_eq!(spooled(&spool).await, vec![3, 4]);
+    }
+}
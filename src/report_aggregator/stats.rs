@@ -0,0 +1,236 @@
+//! Pre-aggregation of traces into [ContextualizedStats].
+//!
+//! Follows the algorithm used by Apollo Server to build field usage stats from a trace tree:
+//! <https://github.com/apollographql/apollo-server/blob/main/packages/server/src/plugin/usageReporting/stats.ts>
+use std::collections::HashMap;
+
+use crate::proto::reports::{
+    trace::{cache_policy::Scope, node, Node},
+    ContextualizedStats, FieldStat, QueryLatencyStats, StatsContext, Trace, TypeStat,
+};
+
+use super::duration_histogram::DurationHistogram;
+
+/// Key used to group stats of the same operation, mirrors [StatsContext].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StatsContextKey {
+    client_name: String,
+    client_version: String,
+    operation_type: String,
+    operation_subtype: String,
+}
+
+impl From<&Trace> for StatsContextKey {
+    fn from(trace: &Trace) -> Self {
+        Self {
+            client_name: trace.client_name.clone(),
+            client_version: trace.client_version.clone(),
+            operation_type: trace.operation_type.clone(),
+            operation_subtype: trace.operation_subtype.clone(),
+        }
+    }
+}
+
+impl From<StatsContextKey> for StatsContext {
+    fn from(key: StatsContextKey) -> Self {
+        StatsContext {
+            client_name: key.client_name,
+            client_version: key.client_version,
+            operation_type: key.operation_type,
+            operation_subtype: key.operation_subtype,
+            special_fields: Default::default(),
+        }
+    }
+}
+
+/// Stats of one operation signature, split by [StatsContext].
+#[derive(Debug, Default)]
+pub struct OperationStats {
+    per_context: HashMap<StatsContextKey, ContextStats>,
+}
+
+impl OperationStats {
+    /// Aggregate a trace into the stats of its context.
+    pub fn add_trace(&mut self, trace: &Trace) {
+        let context = self
+            .per_context
+            .entry(StatsContextKey::from(trace))
+            .or_default();
+
+        context.request_count += 1;
+        // Like Apollo Server, a registration counts as a miss: the hash alone wasn't enough.
+        if trace.persisted_query_hit {
+            context.persisted_query_hits += 1;
+        }
+        if trace.persisted_query_register {
+            context.persisted_query_misses += 1;
+        }
+        if trace.full_query_cache_hit {
+            context.cache_hits += 1;
+            context
+                .cache_latency
+                .increment_duration(trace.duration_ns, 1.);
+        } else {
+            context.latency.increment_duration(trace.duration_ns, 1.);
+
+            // Only cacheable responses have a cache policy.
+            if let Some(cache_policy) = trace.cache_policy.as_ref() {
+                if let Ok(max_age_ns) = u64::try_from(cache_policy.max_age_ns) {
+                    match cache_policy.scope.enum_value() {
+                        Ok(Scope::PUBLIC) => {
+                            context.public_cache_ttl.increment_duration(max_age_ns, 1.)
+                        }
+                        Ok(Scope::PRIVATE) => {
+                            context.private_cache_ttl.increment_duration(max_age_ns, 1.)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        // Requests without field-level instrumentation have a weight of 0, they don't contribute
+        // to the field stats.
+        let weight = trace.field_execution_weight;
+        if weight <= 0. {
+            context.requests_without_field_instrumentation += 1;
+        }
+
+        if let Some(root) = trace.root.as_ref() {
+            if context.add_node(root, weight) {
+                context.requests_with_errors_count += 1;
+            }
+        }
+    }
+
+    pub fn into_proto(self) -> Vec<ContextualizedStats> {
+        self.per_context
+            .into_iter()
+            .map(|(key, stats)| stats.into_proto(key))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct ContextStats {
+    request_count: u64,
+    cache_hits: u64,
+    persisted_query_hits: u64,
+    persisted_query_misses: u64,
+    cache_latency: DurationHistogram,
+    public_cache_ttl: DurationHistogram,
+    private_cache_ttl: DurationHistogram,
+    requests_with_errors_count: u64,
+    requests_without_field_instrumentation: u64,
+    latency: DurationHistogram,
+    per_type: HashMap<String, HashMap<String, FieldStats>>,
+}
+
+impl ContextStats {
+    /// Aggregate the stats of a node and its children, returns `true` if any of them has an
+    /// error.
+    fn add_node(&mut self, node: &Node, weight: f64) -> bool {
+        let mut has_error = !node.error.is_empty();
+
+        let field_name = match &node.id {
+            _ if !node.original_field_name.is_empty() => node.original_field_name.as_str(),
+            Some(node::Id::ResponseName(name)) => name.as_str(),
+            _ => "",
+        };
+
+        if weight > 0.
+            && !node.parent_type.is_empty()
+            && !field_name.is_empty()
+            && !node.type_.is_empty()
+            && node.end_time >= node.start_time
+        {
+            let field_stats = self
+                .per_type
+                .entry(node.parent_type.clone())
+                .or_default()
+                .entry(field_name.to_string())
+                .or_insert_with(|| FieldStats {
+                    return_type: node.type_.clone(),
+                    ..Default::default()
+                });
+
+            field_stats.errors_count += node.error.len() as u64;
+            field_stats.observed_execution_count += 1;
+            field_stats.estimated_execution_count += weight;
+            if !node.error.is_empty() {
+                field_stats.requests_with_errors_count += 1;
+            }
+            field_stats
+                .latency
+                .increment_duration(node.end_time - node.start_time, weight);
+        }
+
+        for child in &node.child {
+            has_error |= self.add_node(child, weight);
+        }
+
+        has_error
+    }
+
+    fn into_proto(self, key: StatsContextKey) -> ContextualizedStats {
+        let per_type_stat = self
+            .per_type
+            .into_iter()
+            .map(|(type_name, fields)| {
+                let type_stat = TypeStat {
+                    per_field_stat: fields
+                        .into_iter()
+                        .map(|(field_name, stats)| (field_name, stats.into_proto()))
+                        .collect(),
+                    special_fields: Default::default(),
+                };
+                (type_name, type_stat)
+            })
+            .collect();
+
+        let query_latency_stats = QueryLatencyStats {
+            latency_count: self.latency.into_proto(),
+            request_count: self.request_count,
+            cache_hits: self.cache_hits,
+            persisted_query_hits: self.persisted_query_hits,
+            persisted_query_misses: self.persisted_query_misses,
+            cache_latency_count: self.cache_latency.into_proto(),
+            public_cache_ttl_count: self.public_cache_ttl.into_proto(),
+            private_cache_ttl_count: self.private_cache_ttl.into_proto(),
+            requests_with_errors_count: self.requests_with_errors_count,
+            requests_without_field_instrumentation: self.requests_without_field_instrumentation,
+            ..Default::default()
+        };
+
+        ContextualizedStats {
+            context: Some(StatsContext::from(key)).into(),
+            query_latency_stats: Some(query_latency_stats).into(),
+            per_type_stat,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct FieldStats {
+    return_type: String,
+    errors_count: u64,
+    observed_execution_count: u64,
+    estimated_execution_count: f64,
+    requests_with_errors_count: u64,
+    latency: DurationHistogram,
+}
+
+impl FieldStats {
+    fn into_proto(self) -> FieldStat {
+        FieldStat {
+            return_type: self.return_type,
+            errors_count: self.errors_count,
+            observed_execution_count: self.observed_execution_count,
+            estimated_execution_count: self.estimated_execution_count.round() as u64,
+            requests_with_errors_count: self.requests_with_errors_count,
+            latency_count: self.latency.into_proto(),
+            ..Default::default()
+        }
+    }
+}
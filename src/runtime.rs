@@ -9,15 +9,15 @@ cfg_if::cfg_if! {
             handle.abort();
         }
 
-        pub struct Instant(tokio::time::Instant);
-        impl Instant {
-            pub fn now() -> Instant {
-                Instant(tokio::time::Instant::now())
-            }
-
-            pub fn elapsed(&self) -> std::time::Duration {
-                self.0.elapsed()
-            }
+        pub async fn sleep(duration: std::time::Duration) {
+            tokio::time::sleep(duration).await
+        }
+
+        /// Run `f` on a thread where blocking is fine, like file system calls.
+        pub async fn spawn_blocking<T: Send + 'static>(
+            f: impl FnOnce() -> T + Send + 'static,
+        ) -> anyhow::Result<T> {
+            Ok(tokio::task::spawn_blocking(f).await?)
         }
     } else {
         pub struct JoinHandle<T: Send + 'static>(std::marker::PhantomData<T>);
@@ -29,15 +29,35 @@ cfg_if::cfg_if! {
 
         pub fn abort(_handle: &JoinHandle<()>) {}
 
-        pub struct Instant(std::time::Instant);
-        impl Instant {
-            pub fn now() -> Instant {
-                Instant(std::time::Instant::now())
-            }
+        pub async fn sleep(duration: std::time::Duration) {
+            gloo_timers::future::sleep(duration).await
+        }
 
-            pub fn elapsed(&self) -> std::time::Duration {
-                self.0.elapsed()
-            }
+        pub async fn spawn_blocking<T: Send + 'static>(
+            f: impl FnOnce() -> T + Send + 'static,
+        ) -> anyhow::Result<T> {
+            Ok(f())
         }
     }
 }
+
+/// Run `future` until it completes or `duration` elapses, `None` is returned in the latter case.
+pub async fn timeout<F: std::future::Future>(
+    duration: std::time::Duration,
+    future: F,
+) -> Option<F::Output> {
+    let sleep = sleep(duration);
+    futures::pin_mut!(future, sleep);
+    match futures::future::select(future, sleep).await {
+        futures::future::Either::Left((output, _)) => Some(output),
+        futures::future::Either::Right(_) => None,
+    }
+}
+
+/// A stream ticking every `period`, the first tick happens after one `period`.
+pub fn interval(period: std::time::Duration) -> impl futures::Stream<Item = ()> {
+    futures::stream::unfold((), move |_| async move {
+        sleep(period).await;
+        Some(((), ()))
+    })
+}
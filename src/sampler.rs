@@ -0,0 +1,117 @@
+//! # Trace sampling
+//!
+//! Building the trace tree of a request means instrumenting every resolver, which has a cost on
+//! busy services. The [TraceSampler] decides which requests are instrumented and sent as traces,
+//! the others only contribute to the request-level stats.
+use std::collections::HashMap;
+
+/// Decide which requests get field-level instrumentation and are sent as traces.
+///
+/// Sampled requests are weighted by the inverse of their sampling ratio so the estimated field
+/// execution counts in Apollo Studio stay correct.
+#[derive(Debug, Clone)]
+pub struct TraceSampler {
+    ratio: f64,
+    per_operation: HashMap<String, f64>,
+    keep_errors: bool,
+}
+
+impl Default for TraceSampler {
+    /// Every request is sampled.
+    fn default() -> Self {
+        Self::ratio(1.)
+    }
+}
+
+impl TraceSampler {
+    /// Sample a fixed ratio of the requests, between `0.0` (none) and `1.0` (all).
+    pub fn ratio(ratio: f64) -> Self {
+        Self {
+            ratio: ratio.clamp(0., 1.),
+            per_operation: HashMap::new(),
+            keep_errors: false,
+        }
+    }
+
+    /// Use a different ratio for the operations named `operation_name`.
+    pub fn with_operation_ratio(mut self, operation_name: impl Into<String>, ratio: f64) -> Self {
+        self.per_operation
+            .insert(operation_name.into(), ratio.clamp(0., 1.));
+        self
+    }
+
+    /// Send a trace for every request ending with errors, even when it wasn't sampled. As those
+    /// requests weren't instrumented, their trace only contains the errors on the root node.
+    pub fn keep_errors(mut self, keep_errors: bool) -> Self {
+        self.keep_errors = keep_errors;
+        self
+    }
+
+    pub(crate) fn keeps_errors(&self) -> bool {
+        self.keep_errors
+    }
+
+    /// Decide if a request should be sampled, returns the field execution weight of the request
+    /// when it is.
+    pub(crate) fn sample(&self, operation_name: Option<&str>) -> Option<f64> {
+        let ratio = operation_name
+            .and_then(|name| self.per_operation.get(name))
+            .copied()
+            .unwrap_or(self.ratio);
+
+        if ratio > 0. && fastrand::f64() < ratio {
+            Some(1. / ratio)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Weights of 1000 sampling decisions, `None` for the requests sampled out.
+    fn sample(sampler: &TraceSampler, operation_name: Option<&str>) -> Vec<Option<f64>> {
+        fastrand::seed(7);
+        (0..1000).map(|_| sampler.sample(operation_name)).collect()
+    }
+
+    fn sampled(weights: &[Option<f64>]) -> usize {
+        weights.iter().flatten().count()
+    }
+
+    #[test]
+    fn sample_every_request_by_default() {
+        let weights = sample(&TraceSampler::default(), Some("Op"));
+        assert!(weights.iter().all(|weight| *weight == Some(1.)));
+    }
+
+    #[test]
+    fn sample_a_ratio_of_the_requests() {
+        let weights = sample(&TraceSampler::ratio(0.25), Some("Op"));
+        assert!((200..300).contains(&sampled(&weights)));
+        assert!(weights.iter().flatten().all(|weight| *weight == 4.));
+
+        assert_eq!(sampled(&sample(&TraceSampler::ratio(0.), None)), 0);
+        assert_eq!(sampled(&sample(&TraceSampler::ratio(-1.), None)), 0);
+        assert_eq!(sampled(&sample(&TraceSampler::ratio(2.), None)), 1000);
+    }
+
+    #[test]
+    fn sample_operations_at_their_own_ratio() {
+        let sampler = TraceSampler::ratio(0.).with_operation_ratio("Op", 0.5);
+        let weights = sample(&sampler, Some("Op"));
+        assert!((400..600).contains(&sampled(&weights)));
+        assert!(weights.iter().flatten().all(|weight| *weight == 2.));
+
+        assert_eq!(sampled(&sample(&sampler, Some("Other"))), 0);
+        assert_eq!(sampled(&sample(&sampler, None)), 0);
+    }
+
+    #[test]
+    fn keep_errors_when_asked() {
+        assert!(!TraceSampler::default().keeps_errors());
+        assert!(TraceSampler::ratio(0.).keep_errors(true).keeps_errors());
+    }
+}
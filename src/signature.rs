@@ -0,0 +1,396 @@
+//! # Operation signature
+//!
+//! Normalize an operation the same way Apollo does for usage reporting, so every execution of
+//! the same operation ends up under the same key in Apollo Studio whatever the literals, aliases,
+//! field order or whitespace sent by the client.
+//!
+//! <https://www.apollographql.com/docs/studio/metrics/operation-signatures/>
+//!
+//! The signature is computed by:
+//! * Dropping every operation but the executed one, and every fragment it doesn't use.
+//! * Hiding literals: numbers become `0`, strings `""`, lists `[]` and objects `{}`.
+//! * Removing aliases.
+//! * Sorting definitions, selections, arguments, variables and some directives.
+//! * Printing the result with the minimum amount of whitespace.
+use std::collections::{BTreeSet, HashMap};
+
+use async_graphql::parser::types::{
+    BaseType, Directive, DocumentOperations, ExecutableDocument, FragmentDefinition,
+    OperationDefinition, OperationType, Selection, SelectionSet, Type, VariableDefinition,
+};
+use async_graphql::parser::Positioned;
+use async_graphql_value::{ConstValue, Name, Value};
+
+/// Compute the key used to report the executed operation to Apollo Studio:
+/// `# {operation_name}\n{signature}`.
+///
+/// The operation is selected like async_graphql does it when executing the document. `None` is
+/// returned when no operation would be executed.
+pub fn usage_reporting_key(
+    document: &ExecutableDocument,
+    operation_name: Option<&str>,
+) -> Option<String> {
+    let (name, operation) = find_operation(&document.operations, operation_name)?;
+    let signature = signature(document, name, operation);
+    Some(format!("# {}\n{}", name.unwrap_or("-"), signature))
+}
+
+/// Find the operation which will be executed for this `operation_name`.
+pub fn find_operation<'a>(
+    operations: &'a DocumentOperations,
+    operation_name: Option<&str>,
+) -> Option<(Option<&'a str>, &'a OperationDefinition)> {
+    let found = match operation_name {
+        Some(operation_name) => operations
+            .iter()
+            .find(|(name, _)| name.map(|x| x.as_str()) == Some(operation_name)),
+        None => {
+            let mut iter = operations.iter();
+            match (iter.next(), iter.next()) {
+                (Some(operation), None) => Some(operation),
+                _ => None,
+            }
+        }
+    };
+
+    found.map(|(name, operation)| (name.map(|x| x.as_str()), &operation.node))
+}
+
+fn signature(
+    document: &ExecutableDocument,
+    name: Option<&str>,
+    operation: &OperationDefinition,
+) -> String {
+    let mut used_fragments = BTreeSet::new();
+    collect_fragments(
+        &operation.selection_set.node,
+        &document.fragments,
+        &mut used_fragments,
+    );
+
+    let mut printer = Printer::default();
+    // Fragment definitions are sorted by name and printed before the operation.
+    for fragment_name in used_fragments {
+        if let Some(fragment) = document.fragments.get(fragment_name) {
+            printer.fragment_definition(fragment_name, &fragment.node);
+        }
+    }
+    printer.operation(name, operation);
+    printer.out
+}
+
+/// Collect the fragments used by a selection set, following nested fragment spreads.
+fn collect_fragments<'a>(
+    selection_set: &'a SelectionSet,
+    fragments: &'a HashMap<Name, Positioned<FragmentDefinition>>,
+    used: &mut BTreeSet<&'a str>,
+) {
+    for selection in &selection_set.items {
+        match &selection.node {
+            Selection::Field(field) => {
+                collect_fragments(&field.node.selection_set.node, fragments, used)
+            }
+            Selection::InlineFragment(fragment) => {
+                collect_fragments(&fragment.node.selection_set.node, fragments, used)
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.node.fragment_name.node.as_str();
+                if used.insert(name) {
+                    if let Some(fragment) = fragments.get(name) {
+                        collect_fragments(&fragment.node.selection_set.node, fragments, used);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c == '_' || c.is_ascii_alphanumeric()
+}
+
+/// Print a GraphQL document with reduced whitespace: tokens are only separated by a single space
+/// when both of them are words.
+#[derive(Default)]
+struct Printer {
+    out: String,
+}
+
+impl Printer {
+    fn token(&mut self, token: &str) {
+        if let (Some(last), Some(first)) = (self.out.chars().last(), token.chars().next()) {
+            if is_word_char(last) && is_word_char(first) {
+                self.out.push(' ');
+            }
+        }
+        self.out.push_str(token);
+    }
+
+    fn operation(&mut self, name: Option<&str>, operation: &OperationDefinition) {
+        let ty = match operation.ty {
+            OperationType::Query => "query",
+            OperationType::Mutation => "mutation",
+            OperationType::Subscription => "subscription",
+        };
+
+        // Anonymous queries without variables nor directives use the shorthand syntax.
+        let shorthand = name.is_none()
+            && operation.ty == OperationType::Query
+            && operation.variable_definitions.is_empty()
+            && operation.directives.is_empty();
+
+        if !shorthand {
+            self.token(ty);
+            if let Some(name) = name {
+                self.token(name);
+            }
+            self.variable_definitions(&operation.variable_definitions);
+            self.directives(operation.directives.iter().map(|x| &x.node), false);
+        }
+        self.selection_set(&operation.selection_set.node);
+    }
+
+    fn fragment_definition(&mut self, name: &str, fragment: &FragmentDefinition) {
+        self.token("fragment");
+        self.token(name);
+        self.token("on");
+        self.token(&fragment.type_condition.node.on.node);
+        self.directives(fragment.directives.iter().map(|x| &x.node), true);
+        self.selection_set(&fragment.selection_set.node);
+    }
+
+    fn variable_definitions(&mut self, definitions: &[Positioned<VariableDefinition>]) {
+        if definitions.is_empty() {
+            return;
+        }
+
+        let mut definitions: Vec<&VariableDefinition> =
+            definitions.iter().map(|x| &x.node).collect();
+        definitions.sort_by(|a, b| a.name.node.cmp(&b.name.node));
+
+        self.token("(");
+        for (index, definition) in definitions.into_iter().enumerate() {
+            if index > 0 {
+                self.token(",");
+            }
+            self.token("$");
+            self.token(&definition.name.node);
+            self.token(":");
+            self.ty(&definition.var_type.node);
+            if let Some(default_value) = &definition.default_value {
+                self.token("=");
+                self.const_value(&default_value.node);
+            }
+            self.directives(definition.directives.iter().map(|x| &x.node), false);
+        }
+        self.token(")");
+    }
+
+    fn ty(&mut self, ty: &Type) {
+        match &ty.base {
+            BaseType::Named(name) => self.token(name),
+            BaseType::List(ty) => {
+                self.token("[");
+                self.ty(ty);
+                self.token("]");
+            }
+        }
+        if !ty.nullable {
+            self.token("!");
+        }
+    }
+
+    fn directives<'a>(&mut self, directives: impl Iterator<Item = &'a Directive>, sort: bool) {
+        let mut directives: Vec<&Directive> = directives.collect();
+        if sort {
+            directives.sort_by(|a, b| a.name.node.cmp(&b.name.node));
+        }
+
+        for directive in directives {
+            self.token("@");
+            self.token(&directive.name.node);
+            self.arguments(&directive.arguments);
+        }
+    }
+
+    fn arguments(&mut self, arguments: &[(Positioned<Name>, Positioned<Value>)]) {
+        if arguments.is_empty() {
+            return;
+        }
+
+        let mut arguments: Vec<(&Name, &Value)> = arguments
+            .iter()
+            .map(|(name, value)| (&name.node, &value.node))
+            .collect();
+        arguments.sort_by(|a, b| a.0.cmp(b.0));
+
+        self.token("(");
+        for (index, (name, value)) in arguments.into_iter().enumerate() {
+            if index > 0 {
+                self.token(",");
+            }
+            self.token(name);
+            self.token(":");
+            self.value(value);
+        }
+        self.token(")");
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Variable(name) => {
+                self.token("$");
+                self.token(name);
+            }
+            Value::Null => self.token("null"),
+            Value::Number(_) => self.token("0"),
+            Value::String(_) | Value::Binary(_) => self.token("\"\""),
+            Value::Boolean(value) => self.token(if *value { "true" } else { "false" }),
+            Value::Enum(name) => self.token(name),
+            Value::List(_) => self.token("[]"),
+            Value::Object(_) => self.token("{}"),
+        }
+    }
+
+    fn const_value(&mut self, value: &ConstValue) {
+        match value {
+            ConstValue::Null => self.token("null"),
+            ConstValue::Number(_) => self.token("0"),
+            ConstValue::String(_) | ConstValue::Binary(_) => self.token("\"\""),
+            ConstValue::Boolean(value) => self.token(if *value { "true" } else { "false" }),
+            ConstValue::Enum(name) => self.token(name),
+            ConstValue::List(_) => self.token("[]"),
+            ConstValue::Object(_) => self.token("{}"),
+        }
+    }
+
+    fn selection_set(&mut self, selection_set: &SelectionSet) {
+        if selection_set.items.is_empty() {
+            return;
+        }
+
+        // Fields first, then fragment spreads, then inline fragments, each sorted by name.
+        // Inline fragments have no name and keep their order.
+        let mut selections: Vec<&Selection> = selection_set.items.iter().map(|x| &x.node).collect();
+        selections.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+
+        self.token("{");
+        for selection in selections {
+            match selection {
+                Selection::Field(field) => {
+                    let field = &field.node;
+                    self.token(&field.name.node);
+                    self.arguments(&field.arguments);
+                    self.directives(field.directives.iter().map(|x| &x.node), false);
+                    self.selection_set(&field.selection_set.node);
+                }
+                Selection::FragmentSpread(spread) => {
+                    let spread = &spread.node;
+                    self.token("...");
+                    self.token(&spread.fragment_name.node);
+                    self.directives(spread.directives.iter().map(|x| &x.node), true);
+                }
+                Selection::InlineFragment(fragment) => {
+                    let fragment = &fragment.node;
+                    self.token("...");
+                    if let Some(type_condition) = &fragment.type_condition {
+                        self.token("on");
+                        self.token(&type_condition.node.on.node);
+                    }
+                    self.directives(fragment.directives.iter().map(|x| &x.node), true);
+                    self.selection_set(&fragment.selection_set.node);
+                }
+            }
+        }
+        self.token("}");
+    }
+}
+
+fn sort_key(selection: &Selection) -> (u8, Option<&str>) {
+    match selection {
+        Selection::Field(field) => (0, Some(field.node.name.node.as_str())),
+        Selection::FragmentSpread(spread) => (1, Some(spread.node.fragment_name.node.as_str())),
+        Selection::InlineFragment(_) => (2, None),
+    }
+}
+
+// Expected signatures are the ones of Apollo's `defaultUsageReportingSignature` tests.
+#[cfg(test)]
+mod tests {
+    use async_graphql::parser::parse_query;
+
+    use super::*;
+
+    fn key(query: &str, operation_name: Option<&str>) -> String {
+        let document = parse_query(query).unwrap();
+        usage_reporting_key(&document, operation_name).unwrap()
+    }
+
+    #[test]
+    fn anonymous_operations() {
+        assert_eq!(key("{ user { name } }", None), "# -\n{user{name}}");
+        assert_eq!(key("query { user { name } }", None), "# -\n{user{name}}");
+    }
+
+    #[test]
+    fn named_operation() {
+        assert_eq!(
+            key("query OpName { user { name } }", Some("OpName")),
+            "# OpName\nquery OpName{user{name}}"
+        );
+    }
+
+    #[test]
+    fn hide_literals() {
+        assert_eq!(
+            key(
+                r#"query OpName { user { name(apple: [[10]], cat: ENUM_VALUE, bag: {input: "value"}) } }"#,
+                Some("OpName"),
+            ),
+            "# OpName\nquery OpName{user{name(apple:[],bag:{},cat:ENUM_VALUE)}}"
+        );
+    }
+
+    #[test]
+    fn sort_variables_and_arguments() {
+        assert_eq!(
+            key(
+                "query OpName($c: Int!, $a: [[Boolean!]!], $b: EnumType) { user { name(apple: $a, cat: $c, bag: $b) } }",
+                Some("OpName"),
+            ),
+            "# OpName\nquery OpName($a:[[Boolean!]!],$b:EnumType,$c:Int!){user{name(apple:$a,bag:$b,cat:$c)}}"
+        );
+    }
+
+    #[test]
+    fn drop_unused_fragments() {
+        assert_eq!(
+            key(
+                "{ user { name ...Bar } } fragment Bar on User { asd } fragment Baz on User { jkl }",
+                None,
+            ),
+            "# -\nfragment Bar on User{asd}{user{name...Bar}}"
+        );
+    }
+
+    #[test]
+    fn full_normalization() {
+        assert_eq!(
+            key(
+                r#"query Foo($b: Int, $a: Boolean) { user(name: "hello", age: 5) { ...Bar ... on User { hello bee } tz aliased: name } } fragment Baz on User { asd } fragment Bar on User { age @skip(if: $a) ...Nested } fragment Nested on User { blah }"#,
+                Some("Foo"),
+            ),
+            r#"# Foo
+fragment Bar on User{age@skip(if:$a)...Nested}fragment Nested on User{blah}query Foo($a:Boolean,$b:Int){user(age:0,name:""){name tz...Bar...on User{bee hello}}}"#
+        );
+    }
+
+    #[test]
+    fn select_the_executed_operation() {
+        let query = "query A { a } query B { b }";
+        assert_eq!(key(query, Some("B")), "# B\nquery B{b}");
+        let document = parse_query(query).unwrap();
+        assert_eq!(usage_reporting_key(&document, None), None);
+        assert_eq!(usage_reporting_key(&document, Some("C")), None);
+    }
+}
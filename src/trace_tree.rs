@@ -0,0 +1,463 @@
+//! # Trace tree
+//!
+//! Fields are resolved concurrently and finish before their parent, so the tree of [Node] isn't
+//! built while resolving: each field is recorded once resolved with a hash of its path and of the
+//! path of its parent, hashed from the path nodes without copying them. The tree is built once
+//! the execution ends, by linking each field to the one at the path of its parent, so resolving a
+//! field takes no lock.
+//!
+//! The errors of the response which weren't returned by a resolver, like the ones raised by other
+//! extensions, are then added to the node of their path, or to the root node.
+//!
+//! Port of Apollo Server's `traceTreeBuilder`:
+//! <https://github.com/apollographql/apollo-server/blob/main/packages/server/src/plugin/traceTreeBuilder.ts>
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+use async_graphql::{PathSegment, QueryPathNode, QueryPathSegment, ServerError};
+use chrono::{DateTime, Utc};
+
+use crate::errors::ErrorPolicy;
+use crate::proto::reports::trace::{self, node, Node};
+
+/// Id of the root node, the ids of the fields follow in the order they started to resolve.
+const ROOT: usize = 0;
+
+/// Id of the node at `path_node`: the response name of a field, the alias when there is one, or
+/// the index of a list item.
+fn node_id(path_node: &QueryPathNode<'_>) -> node::Id {
+    match &path_node.segment {
+        QueryPathSegment::Name(name) => node::Id::ResponseName(name.to_string()),
+        QueryPathSegment::Index(index) => node::Id::Index((*index).try_into().unwrap_or(u32::MAX)),
+    }
+}
+
+fn path_segment_id(segment: &PathSegment) -> node::Id {
+    match segment {
+        PathSegment::Field(name) => node::Id::ResponseName(name.clone()),
+        PathSegment::Index(index) => node::Id::Index((*index).try_into().unwrap_or(u32::MAX)),
+    }
+}
+
+fn is_segment(segment: &QueryPathSegment<'_>, other: &PathSegment) -> bool {
+    match (segment, other) {
+        (QueryPathSegment::Name(name), PathSegment::Field(field)) => name == field,
+        (QueryPathSegment::Index(index), PathSegment::Index(other)) => index == other,
+        _ => false,
+    }
+}
+
+fn is_node_at(id: Option<&node::Id>, segment: &PathSegment) -> bool {
+    match (id, segment) {
+        (Some(node::Id::ResponseName(name)), PathSegment::Field(field)) => name == field,
+        (Some(node::Id::Index(index)), PathSegment::Index(other)) => {
+            usize::try_from(*index).is_ok_and(|index| index == *other)
+        }
+        _ => false,
+    }
+}
+
+/// Whether the error was raised at this path. Errors of non-null fields are also returned by the
+/// resolvers of their parents, up to the first nullable one, they only belong to the first node.
+/// Items of lists change the path of the errors, they can't be told apart this way.
+pub fn is_raised_at(path_node: &QueryPathNode<'_>, error: &ServerError) -> bool {
+    if error.path.is_empty() {
+        return true;
+    }
+    let mut segments = error.path.iter().rev();
+    let same_path = std::iter::once(path_node)
+        .chain(path_node.parents())
+        .all(|node| {
+            segments
+                .next()
+                .is_some_and(|s| is_segment(&node.segment, s))
+        });
+    same_path && segments.next().is_none()
+}
+
+/// Whether both are the same error, whatever their path.
+fn is_same_error(a: &ServerError, b: &ServerError) -> bool {
+    a.message == b.message && a.locations == b.locations
+}
+
+/// Hash of the path of a field, which identifies it within a response.
+type PathHash = u64;
+
+/// Hash of the empty path, the one of the root.
+const ROOT_PATH: PathHash = 0;
+
+fn path_hash(path_node: &QueryPathNode<'_>) -> PathHash {
+    let parent = path_node.parent.map_or(ROOT_PATH, path_hash);
+    child_path_hash(parent, &path_node.segment)
+}
+
+fn child_path_hash(parent: PathHash, segment: &QueryPathSegment<'_>) -> PathHash {
+    let mut hasher = DefaultHasher::new();
+    parent.hash(&mut hasher);
+    match segment {
+        QueryPathSegment::Name(name) => (0_u8, name).hash(&mut hasher),
+        QueryPathSegment::Index(index) => (1_u8, index).hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+/// A field which started to resolve, to [record](TraceTreeBuilder::record) once it's resolved.
+pub struct Entered {
+    id: usize,
+    path: PathHash,
+    parent: PathHash,
+    node_id: node::Id,
+}
+
+struct ResolvedField {
+    id: usize,
+    path: PathHash,
+    parent: PathHash,
+    node: Node,
+    error: Option<ServerError>,
+}
+
+/// Collect the fields resolved during an execution, then build their tree.
+pub struct TraceTreeBuilder {
+    /// Start of the execution in nanoseconds since the epoch, times of the nodes are relative to
+    /// it.
+    start_ns: AtomicI64,
+    /// Ids of the fields, in the order they started to resolve.
+    next_id: AtomicUsize,
+    sender: Sender<ResolvedField>,
+    /// Only read when the tree is built.
+    receiver: Mutex<Receiver<ResolvedField>>,
+}
+
+impl Default for TraceTreeBuilder {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            start_ns: AtomicI64::new(0),
+            next_id: AtomicUsize::new(ROOT + 1),
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+impl TraceTreeBuilder {
+    /// Start a new execution, the time of its nodes are relative to `start_time`.
+    pub fn start(&self, start_time: DateTime<Utc>) {
+        self.start_ns.store(
+            start_time.timestamp_nanos_opt().unwrap_or_default(),
+            Ordering::Relaxed,
+        );
+        self.next_id.store(ROOT + 1, Ordering::Relaxed);
+    }
+
+    /// Nanoseconds elapsed since the start of the execution.
+    pub fn elapsed_ns(&self) -> u64 {
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        u64::try_from(now - self.start_ns.load(Ordering::Relaxed)).unwrap_or_default()
+    }
+
+    /// Identify the field at `path_node` when it starts to resolve.
+    pub fn enter(&self, path_node: &QueryPathNode<'_>) -> Entered {
+        let parent = path_node.parent.map_or(ROOT_PATH, path_hash);
+        Entered {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            path: child_path_hash(parent, &path_node.segment),
+            parent,
+            node_id: node_id(path_node),
+        }
+    }
+
+    /// Record a resolved field and the error it returned, its `id`, `child` and `error` are
+    /// filled when the tree is built.
+    pub fn record(&self, entered: Entered, mut node: Node, error: Option<ServerError>) {
+        node.id = Some(entered.node_id);
+        // The receiver lives as long as the sender, this can't fail.
+        let _ = self.sender.send(ResolvedField {
+            id: entered.id,
+            path: entered.path,
+            parent: entered.parent,
+            node,
+            error,
+        });
+    }
+
+    /// Build the tree of the fields recorded since the start of the execution, and return its
+    /// root. The `errors` of the response not returned by a resolver are added to the tree too.
+    pub fn build(&self, errors: &[ServerError], policy: &ErrorPolicy) -> Node {
+        let receiver = self.receiver.lock().unwrap();
+        let fields: Vec<_> = receiver.try_iter().collect();
+        let ids: HashMap<PathHash, usize> =
+            fields.iter().map(|field| (field.path, field.id)).collect();
+
+        let mut tree = Tree::new(self.next_id.load(Ordering::Relaxed));
+        let mut resolver_errors = Vec::new();
+        for mut field in fields {
+            if let Some(error) = field.error.take() {
+                field.node.error = trace_errors(policy, &error, field.node.end_time);
+                resolver_errors.push(error);
+            }
+            // Parents are always recorded, the root is only a fallback.
+            let parent = ids.get(&field.parent).copied().unwrap_or(ROOT);
+            tree.insert(field.id, parent, field.node);
+        }
+        tree.link();
+
+        let time_ns = self.elapsed_ns();
+        let other_errors = errors.iter().filter(|error| {
+            !resolver_errors
+                .iter()
+                .any(|resolver_error| is_same_error(error, resolver_error))
+        });
+        for error in other_errors {
+            let index = tree.index_of(&error.path);
+            tree.nodes[index]
+                .error
+                .extend(trace_errors(policy, error, time_ns));
+        }
+        tree.into_root()
+    }
+}
+
+fn trace_errors(policy: &ErrorPolicy, error: &ServerError, time_ns: u64) -> Vec<trace::Error> {
+    let mut errors = policy.trace_errors([error]);
+    for error in &mut errors {
+        error.time_ns = time_ns;
+    }
+    errors
+}
+
+/// Arena of the nodes indexed by their id, the root being the first one. Children are linked by
+/// index, and only moved into their parent once every node is there.
+struct Tree {
+    nodes: Vec<Node>,
+    parents: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+}
+
+impl Tree {
+    fn new(len: usize) -> Self {
+        Self {
+            nodes: vec![Node::default(); len],
+            parents: vec![None; len],
+            children: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, id: usize, parent: usize, node: Node) {
+        if id < self.nodes.len() {
+            self.nodes[id] = node;
+            self.parents[id] = Some(parent);
+        }
+    }
+
+    /// Link the inserted nodes to their parent, ids are given in the order the fields started,
+    /// so they're also linked in this order.
+    fn link(&mut self) {
+        self.children = vec![Vec::new(); self.nodes.len()];
+        for (id, parent) in self.parents.iter().enumerate() {
+            if let Some(parent) = parent {
+                self.children[*parent].push(id);
+            }
+        }
+    }
+
+    /// Index of the node at this path, created with its missing ancestors when it wasn't
+    /// resolved.
+    fn index_of(&mut self, path: &[PathSegment]) -> usize {
+        let mut index = ROOT;
+        for segment in path {
+            let child = self.children[index]
+                .iter()
+                .copied()
+                .find(|&child| is_node_at(self.nodes[child].id.as_ref(), segment));
+            index = match child {
+                Some(child) => child,
+                None => self.add(
+                    index,
+                    Node {
+                        id: Some(path_segment_id(segment)),
+                        ..Default::default()
+                    },
+                ),
+            };
+        }
+        index
+    }
+
+    fn add(&mut self, parent: usize, node: Node) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(node);
+        self.parents.push(Some(parent));
+        self.children.push(Vec::new());
+        self.children[parent].push(index);
+        index
+    }
+
+    fn into_root(mut self) -> Node {
+        self.take(ROOT)
+    }
+
+    fn take(&mut self, index: usize) -> Node {
+        let mut node = std::mem::take(&mut self.nodes[index]);
+        let mut children = std::mem::take(&mut self.children[index]);
+        // Items are sorted by index, fields keep the order they started in, like the selection
+        // order.
+        children.sort_by_key(|&child| match self.nodes[child].id {
+            Some(node::Id::Index(index)) => index as usize,
+            _ => child,
+        });
+        node.child = children.into_iter().map(|child| self.take(child)).collect();
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::Pos;
+
+    use super::*;
+
+    fn name<'a>(parent: Option<&'a QueryPathNode<'a>>, name: &'a str) -> QueryPathNode<'a> {
+        QueryPathNode {
+            parent,
+            segment: QueryPathSegment::Name(name),
+        }
+    }
+
+    fn index<'a>(parent: &'a QueryPathNode<'a>, index: usize) -> QueryPathNode<'a> {
+        QueryPathNode {
+            parent: Some(parent),
+            segment: QueryPathSegment::Index(index),
+        }
+    }
+
+    fn field(field_name: &str) -> Node {
+        Node {
+            original_field_name: field_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn error(message: &str, path: Vec<PathSegment>) -> ServerError {
+        let mut error = ServerError::new(message, Some(Pos { line: 1, column: 3 }));
+        error.path = path;
+        error
+    }
+
+    fn ids(node: &Node) -> Vec<node::Id> {
+        node.child
+            .iter()
+            .filter_map(|child| child.id.clone())
+            .collect()
+    }
+
+    fn response_name(name: &str) -> node::Id {
+        node::Id::ResponseName(name.to_string())
+    }
+
+    #[test]
+    fn list_items() {
+        let builder = TraceTreeBuilder::default();
+        let items = name(None, "items");
+        let entered_items = builder.enter(&items);
+        let first = index(&items, 0);
+        let second = index(&items, 1);
+        let entered_first = builder.enter(&first);
+        let entered_second = builder.enter(&second);
+        // async_graphql copies the path node of the parent in the context of its children.
+        let second_copy = second;
+        let second_id = name(Some(&second_copy), "id");
+        let entered_second_id = builder.enter(&second_id);
+        let first_id = name(Some(&first), "id");
+        let entered_first_id = builder.enter(&first_id);
+
+        // Items finish in any order.
+        builder.record(entered_second_id, field("id"), None);
+        builder.record(entered_second, Node::default(), None);
+        builder.record(entered_first_id, field("id"), None);
+        builder.record(entered_first, Node::default(), None);
+        builder.record(entered_items, field("items"), None);
+
+        let root = builder.build(&[], &ErrorPolicy::unmodified());
+        assert_eq!(ids(&root), vec![response_name("items")]);
+        let items = &root.child[0];
+        assert_eq!(ids(items), vec![node::Id::Index(0), node::Id::Index(1)]);
+        for item in &items.child {
+            assert_eq!(ids(item), vec![response_name("id")]);
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        let builder = TraceTreeBuilder::default();
+        let first = name(None, "first");
+        let second = name(None, "second");
+        let entered_first = builder.enter(&first);
+        let entered_second = builder.enter(&second);
+        builder.record(entered_second, field("user"), None);
+        builder.record(entered_first, field("user"), None);
+
+        let root = builder.build(&[], &ErrorPolicy::unmodified());
+        // In the order they started, with the field name apart.
+        assert_eq!(
+            ids(&root),
+            vec![response_name("first"), response_name("second")]
+        );
+        assert!(root
+            .child
+            .iter()
+            .all(|child| child.original_field_name == "user"));
+    }
+
+    #[test]
+    fn errors_by_path() {
+        let builder = TraceTreeBuilder::default();
+        let user = name(None, "user");
+        let entered_user = builder.enter(&user);
+        let user_name = name(Some(&user), "name");
+        let entered_name = builder.enter(&user_name);
+
+        let path = vec![
+            PathSegment::Field("user".to_string()),
+            PathSegment::Field("name".to_string()),
+        ];
+        let resolver_error = error("resolver", path.clone());
+        assert!(is_raised_at(&user_name, &resolver_error));
+        // Propagated to the parent of a non-null field, it's only reported once.
+        assert!(!is_raised_at(&user, &resolver_error));
+        builder.record(entered_name, field("name"), Some(resolver_error.clone()));
+        builder.record(entered_user, field("user"), None);
+
+        let errors = [
+            resolver_error,
+            error("request", Vec::new()),
+            error(
+                "unresolved",
+                vec![
+                    PathSegment::Field("user".to_string()),
+                    PathSegment::Field("friends".to_string()),
+                    PathSegment::Index(1),
+                ],
+            ),
+        ];
+        let root = builder.build(&errors, &ErrorPolicy::unmodified());
+
+        let messages =
+            |node: &Node| -> Vec<String> { node.error.iter().map(|e| e.message.clone()).collect() };
+        assert_eq!(messages(&root), vec!["request"]);
+        let user = &root.child[0];
+        assert!(user.error.is_empty());
+        assert_eq!(
+            ids(user),
+            vec![response_name("name"), response_name("friends")]
+        );
+        assert_eq!(messages(&user.child[0]), vec!["resolver"]);
+        let friends = &user.child[1];
+        assert_eq!(ids(friends), vec![node::Id::Index(1)]);
+        assert_eq!(messages(&friends.child[0]), vec!["unresolved"]);
+    }
+}
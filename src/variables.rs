@@ -0,0 +1,235 @@
+//! # Variables capture
+//!
+//! Variables can help a lot to understand why a request was slow or failed, but they can also
+//! contain personal data. None of them are sent to Apollo Studio unless a [VariablesCapture]
+//! policy says otherwise.
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use async_graphql::{Value, Variables};
+
+use crate::packages::serde_json;
+
+type Transform = dyn Fn(&str, &Value) -> Option<Value> + Send + Sync;
+
+#[derive(Clone)]
+enum Policy {
+    None,
+    All,
+    Only(HashSet<String>),
+    AllExcept(HashSet<String>),
+    Transform(Arc<Transform>),
+}
+
+/// Decide which variables are sent with the traces.
+///
+/// Unless every variable is private, the names of every variable are sent: private ones with an
+/// empty string as value, like the Apollo protobuf expects it.
+#[derive(Clone)]
+pub struct VariablesCapture {
+    policy: Policy,
+    max_value_size: usize,
+}
+
+impl Default for VariablesCapture {
+    /// No variable is sent.
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl std::fmt::Debug for VariablesCapture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let policy = match &self.policy {
+            Policy::None => "None",
+            Policy::All => "All",
+            Policy::Only(_) => "Only",
+            Policy::AllExcept(_) => "AllExcept",
+            Policy::Transform(_) => "Transform",
+        };
+        f.debug_struct("VariablesCapture")
+            .field("policy", &policy)
+            .field("max_value_size", &self.max_value_size)
+            .finish()
+    }
+}
+
+impl VariablesCapture {
+    fn with_policy(policy: Policy) -> Self {
+        Self {
+            policy,
+            max_value_size: 4096,
+        }
+    }
+
+    /// Keep every variable private.
+    pub fn none() -> Self {
+        Self::with_policy(Policy::None)
+    }
+
+    /// Send every variable.
+    pub fn all() -> Self {
+        Self::with_policy(Policy::All)
+    }
+
+    /// Only send the variables with these names.
+    pub fn only<I, N>(names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        Self::with_policy(Policy::Only(names.into_iter().map(Into::into).collect()))
+    }
+
+    /// Send every variable but the ones with these names.
+    pub fn all_except<I, N>(names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        Self::with_policy(Policy::AllExcept(
+            names.into_iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// Let `transform` decide what is sent for each variable, from its name and value. The value
+    /// can be changed, for instance to mask part of it, and `None` keeps the variable private.
+    pub fn transform(
+        transform: impl Fn(&str, &Value) -> Option<Value> + Send + Sync + 'static,
+    ) -> Self {
+        Self::with_policy(Policy::Transform(Arc::new(transform)))
+    }
+
+    /// Maximum size of the JSON of a variable, in bytes. Bigger values are sent as private.
+    /// Defaults to 4KiB.
+    pub fn max_value_size(mut self, max_value_size: usize) -> Self {
+        self.max_value_size = max_value_size;
+        self
+    }
+
+    /// Whether variables have to be looked at, every variable is private otherwise.
+    pub(crate) fn is_enabled(&self) -> bool {
+        !matches!(self.policy, Policy::None)
+    }
+
+    /// Build the `variables_json` of the trace details: the JSON of each variable, or an empty
+    /// string for private ones.
+    pub(crate) fn capture(&self, variables: &Variables) -> HashMap<String, String> {
+        variables
+            .iter()
+            .map(|(name, value)| {
+                let name = name.as_str();
+                let json = self
+                    .value(name, value)
+                    .and_then(|value| serde_json::to_string(&value).ok())
+                    .filter(|json| json.len() <= self.max_value_size)
+                    .unwrap_or_default();
+                (name.to_string(), json)
+            })
+            .collect()
+    }
+
+    fn value(&self, name: &str, value: &Value) -> Option<Value> {
+        match &self.policy {
+            Policy::None => None,
+            Policy::All => Some(value.clone()),
+            Policy::Only(names) => names.contains(name).then(|| value.clone()),
+            Policy::AllExcept(names) => (!names.contains(name)).then(|| value.clone()),
+            Policy::Transform(transform) => transform(name, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::value;
+
+    use super::*;
+
+    fn variables() -> Variables {
+        Variables::from_value(value!({
+            "id": 1,
+            "password": "secret",
+            "filter": { "name": "ada" },
+        }))
+    }
+
+    /// Variables as sent in the trace details, sorted by name.
+    fn captured(capture: &VariablesCapture) -> Vec<(String, String)> {
+        let mut captured: Vec<_> = capture.capture(&variables()).into_iter().collect();
+        captured.sort();
+        captured
+    }
+
+    fn json(variables: &[(&str, &str)]) -> Vec<(String, String)> {
+        variables
+            .iter()
+            .map(|(name, json)| (name.to_string(), json.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn keep_every_variable_private_by_default() {
+        assert!(!VariablesCapture::default().is_enabled());
+        assert_eq!(
+            captured(&VariablesCapture::none()),
+            json(&[("filter", ""), ("id", ""), ("password", "")])
+        );
+    }
+
+    #[test]
+    fn send_every_variable() {
+        let capture = VariablesCapture::all();
+        assert!(capture.is_enabled());
+        assert_eq!(
+            captured(&capture),
+            json(&[
+                ("filter", r#"{"name":"ada"}"#),
+                ("id", "1"),
+                ("password", r#""secret""#)
+            ])
+        );
+    }
+
+    #[test]
+    fn send_only_the_allowed_variables() {
+        assert_eq!(
+            captured(&VariablesCapture::only(["id", "unknown"])),
+            json(&[("filter", ""), ("id", "1"), ("password", "")])
+        );
+    }
+
+    #[test]
+    fn keep_the_denied_variables_private() {
+        assert_eq!(
+            captured(&VariablesCapture::all_except(["password"])),
+            json(&[
+                ("filter", r#"{"name":"ada"}"#),
+                ("id", "1"),
+                ("password", "")
+            ])
+        );
+    }
+
+    #[test]
+    fn send_the_transformed_variables() {
+        let capture = VariablesCapture::transform(|name, value| match name {
+            "password" => Some(Value::from("***")),
+            "id" => Some(value.clone()),
+            _ => None,
+        });
+        assert_eq!(
+            captured(&capture),
+            json(&[("filter", ""), ("id", "1"), ("password", r#""***""#)])
+        );
+    }
+
+    #[test]
+    fn keep_the_big_variables_private() {
+        let capture = VariablesCapture::all().max_value_size(8);
+        assert_eq!(
+            captured(&capture),
+            json(&[("filter", ""), ("id", "1"), ("password", r#""secret""#)])
+        );
+    }
+}
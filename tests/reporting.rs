@@ -0,0 +1,437 @@
+//! Operations executed through a schema end up in the reports handed to the sink.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_graphql::{
+    value, EmptyMutation, EmptySubscription, Error, ErrorExtensions, Object, Request, Schema,
+    SimpleObject, Variables,
+};
+use async_graphql_extension_apollo_tracing::{
+    ApolloTracing, ApolloTracingConfig, ApolloTracingDataExt, DeliveryError, ErrorPolicy,
+    HeadersCapture, Report, ReportMode, ReportSink, TraceSampler, VariablesCapture,
+};
+
+/// Keeps the reports it's sent.
+#[derive(Clone, Default)]
+struct CapturingSink(Arc<Mutex<Vec<Report>>>);
+
+#[async_trait::async_trait]
+impl ReportSink for CapturingSink {
+    async fn send(&self, report: &Report) -> Result<(), DeliveryError> {
+        self.0.lock().unwrap().push(report.clone());
+        Ok(())
+    }
+}
+
+/// Fields of items finish in a different order than they started.
+struct Item(u64);
+
+#[Object]
+impl Item {
+    async fn id(&self) -> u64 {
+        tokio::time::sleep(Duration::from_millis(5 - self.0 % 5)).await;
+        self.0
+    }
+
+    async fn children(&self) -> Vec<Item> {
+        tokio::time::sleep(Duration::from_millis(self.0 % 3)).await;
+        vec![Item(self.0 * 10), Item(self.0 * 10 + 1)]
+    }
+}
+
+#[derive(SimpleObject)]
+struct User {
+    id: i32,
+    name: String,
+}
+
+struct Query;
+
+#[Object]
+impl Query {
+    async fn user(&self, id: i32) -> User {
+        User {
+            id,
+            name: format!("user {id}"),
+        }
+    }
+
+    async fn items(&self, count: u64) -> Vec<Item> {
+        (0..count).map(Item).collect()
+    }
+
+    async fn fail(&self) -> async_graphql::Result<i32> {
+        Err("failed".into())
+    }
+
+    async fn forbidden(&self) -> async_graphql::Result<i32> {
+        Err(Error::new("forbidden").extend_with(|_, ext| ext.set("code", "FORBIDDEN")))
+    }
+}
+
+/// The same fields as [Query], with cache hints.
+struct CachedQuery;
+
+#[Object(name = "Query")]
+impl CachedQuery {
+    #[graphql(cache_control(max_age = 60))]
+    async fn user(&self, id: i32) -> User {
+        User {
+            id,
+            name: format!("user {id}"),
+        }
+    }
+}
+
+fn tracing(report_mode: ReportMode) -> (ApolloTracing, CapturingSink) {
+    let sink = CapturingSink::default();
+    let config = ApolloTracingConfig::builder()
+        .authorization_token("token")
+        .graph_ref("test@current")
+        .sink(sink.clone())
+        .build()
+        .unwrap();
+    let tracing = ApolloTracing::from_config(config).with_report_mode(report_mode);
+    (tracing, sink)
+}
+
+/// Execute the requests with `tracing`, then return the reports it sent to `sink`.
+async fn execute_requests(
+    tracing: ApolloTracing,
+    sink: CapturingSink,
+    requests: Vec<Request>,
+) -> Vec<Report> {
+    let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+        .extension(tracing.clone())
+        .finish();
+    for request in requests {
+        schema.execute(request).await;
+    }
+    tracing.shutdown(Duration::from_secs(5)).await.unwrap();
+    let reports = sink.0.lock().unwrap().clone();
+    reports
+}
+
+async fn execute(report_mode: ReportMode, queries: &[&str]) -> Vec<Report> {
+    let (tracing, sink) = tracing(report_mode);
+    let requests = queries.iter().map(|query| Request::new(*query)).collect();
+    execute_requests(tracing, sink, requests).await
+}
+
+const USER_KEY: &str = "# User\nquery User{user(id:0){id name}}";
+const FAIL_KEY: &str = "# Fail\nquery Fail{fail}";
+
+#[tokio::test]
+async fn report_traces_and_stats() {
+    let reports = execute(
+        ReportMode::TracesAndStats,
+        &[
+            "query User { user(id: 1) { id name } }",
+            "query User { user(id: 2) { name id } }",
+            "query Fail { fail }",
+        ],
+    )
+    .await;
+    assert_eq!(reports.len(), 1);
+    let report = &reports[0];
+    assert_eq!(report.header.graph_ref, "test@current");
+    assert_eq!(report.operation_count, 3);
+
+    let mut keys: Vec<_> = report.traces_per_query.keys().collect();
+    keys.sort();
+    assert_eq!(keys, vec![FAIL_KEY, USER_KEY]);
+
+    let user = &report.traces_per_query[USER_KEY];
+    assert_eq!(user.trace.len(), 2);
+    assert_eq!(user.trace[0].field_execution_weight, 1.);
+    let root = &user.trace[0].root;
+    assert_eq!(root.child.len(), 1);
+    let field = &root.child[0];
+    assert_eq!(field.response_name(), "user");
+    assert_eq!(field.parent_type, "Query");
+    assert_eq!(field.type_, "User!");
+    assert_eq!(field.child.len(), 2);
+    assert_eq!(
+        user.referenced_fields_by_type["User"].field_names,
+        vec!["id", "name"]
+    );
+
+    let fail = &report.traces_per_query[FAIL_KEY];
+    assert_eq!(fail.trace.len(), 1);
+    let field = &fail.trace[0].root.child[0];
+    assert_eq!(field.response_name(), "fail");
+    assert_eq!(field.error.len(), 1);
+    assert_eq!(field.error[0].message, "failed");
+}
+
+#[tokio::test]
+async fn report_stats_only() {
+    let reports = execute(
+        ReportMode::StatsOnly,
+        &[
+            "query User { user(id: 1) { id name } }",
+            "query User { user(id: 2) { id name } }",
+            "query Fail { fail }",
+        ],
+    )
+    .await;
+    assert_eq!(reports.len(), 1);
+    let report = &reports[0];
+    assert_eq!(report.operation_count, 3);
+
+    let user = &report.traces_per_query[USER_KEY];
+    assert!(user.trace.is_empty());
+    let requests: u64 = user
+        .stats_with_context
+        .iter()
+        .map(|stats| stats.query_latency_stats.request_count)
+        .sum();
+    assert_eq!(requests, 2);
+    let fields = &user.stats_with_context[0].per_type_stat["User"].per_field_stat;
+    assert_eq!(fields["name"].observed_execution_count, 2);
+
+    let fail = &report.traces_per_query[FAIL_KEY];
+    let errors: u64 = fail
+        .stats_with_context
+        .iter()
+        .map(|stats| stats.query_latency_stats.requests_with_errors_count)
+        .sum();
+    assert_eq!(errors, 1);
+}
+
+#[tokio::test]
+async fn build_trees_of_concurrent_fields() {
+    let reports = execute(
+        ReportMode::TracesAndStats,
+        &["query Items { first: items(count: 10) { id children { id } } second: items(count: 10) { id } }"],
+    )
+    .await;
+    let traces = &reports[0].traces_per_query;
+    let root = &traces.values().next().unwrap().trace[0].root;
+
+    macro_rules! names {
+        ($node:expr) => {
+            $node
+                .child
+                .iter()
+                .map(|child| child.response_name())
+                .collect::<Vec<_>>()
+        };
+    }
+    macro_rules! indexes {
+        ($node:expr) => {
+            $node
+                .child
+                .iter()
+                .map(|child| child.index())
+                .collect::<Vec<_>>()
+        };
+    }
+
+    assert_eq!(names!(root), vec!["first", "second"]);
+    let first = &root.child[0];
+    assert_eq!(indexes!(first), (0..10).collect::<Vec<_>>());
+    for item in &first.child {
+        assert_eq!(names!(item), vec!["id", "children"]);
+        let children = &item.child[1];
+        assert_eq!(indexes!(children), vec![0, 1]);
+        for child in &children.child {
+            assert_eq!(names!(child), vec!["id"]);
+        }
+    }
+    let second = &root.child[1];
+    assert_eq!(indexes!(second), (0..10).collect::<Vec<_>>());
+    for item in &second.child {
+        assert_eq!(names!(item), vec!["id"]);
+    }
+}
+
+#[tokio::test]
+async fn keep_the_cache_hints_of_each_schema() {
+    let (tracing, sink) = tracing(ReportMode::TracesAndStats);
+    let cached = Schema::build(CachedQuery, EmptyMutation, EmptySubscription)
+        .extension(tracing.clone())
+        .finish();
+    let uncached = Schema::build(Query, EmptyMutation, EmptySubscription)
+        .extension(tracing.clone())
+        .finish();
+    let query = "query User { user(id: 1) { id } }";
+    cached.execute(Request::new(query)).await;
+    uncached.execute(Request::new(query)).await;
+    cached.execute(Request::new(query)).await;
+    tracing.shutdown(Duration::from_secs(5)).await.unwrap();
+
+    let reports = sink.0.lock().unwrap();
+    let traces = &reports[0].traces_per_query["# User\nquery User{user(id:0){id}}"].trace;
+    let max_ages: Vec<_> = traces
+        .iter()
+        .map(|trace| trace.root.child[0].cache_policy.max_age_ns)
+        .collect();
+    assert_eq!(max_ages, vec![60_000_000_000, 0, 60_000_000_000]);
+}
+
+#[tokio::test]
+async fn keep_the_errored_requests_sampled_out() {
+    let (tracing, sink) = tracing(ReportMode::TracesAndStats);
+    let tracing = tracing.with_sampler(TraceSampler::ratio(0.).keep_errors(true));
+    let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+        .extension(tracing.clone())
+        .finish();
+    for query in [
+        "query User { user(id: 1) { id name } }",
+        "query User { user(id: 2) { id name } }",
+        "query Fail { fail }",
+    ] {
+        schema.execute(Request::new(query)).await;
+    }
+    tracing.shutdown(Duration::from_secs(5)).await.unwrap();
+
+    let reports = sink.0.lock().unwrap();
+    let report = &reports[0];
+    let user = &report.traces_per_query[USER_KEY];
+    assert!(user.trace.is_empty());
+    let stats = &user.stats_with_context[0];
+    assert_eq!(stats.query_latency_stats.request_count, 2);
+    assert_eq!(
+        stats
+            .query_latency_stats
+            .requests_without_field_instrumentation,
+        2
+    );
+    assert!(stats.per_type_stat.is_empty());
+
+    let fail = &report.traces_per_query[FAIL_KEY];
+    assert_eq!(fail.trace.len(), 1);
+    let trace = &fail.trace[0];
+    assert_eq!(trace.field_execution_weight, 0.);
+    assert!(trace.root.child.is_empty());
+    assert_eq!(trace.root.error[0].message, "failed");
+}
+
+/// Status code reported for each query, by operation key.
+async fn status_codes(
+    tracing: ApolloTracing,
+    sink: CapturingSink,
+    requests: Vec<Request>,
+) -> Vec<(String, u32)> {
+    let reports = execute_requests(tracing, sink, requests).await;
+    let mut status_codes: Vec<_> = reports[0]
+        .traces_per_query
+        .iter()
+        .flat_map(|(key, traces)| {
+            traces
+                .trace
+                .iter()
+                .map(|trace| (key.clone(), trace.http.status_code))
+        })
+        .collect();
+    status_codes.sort();
+    status_codes
+}
+
+const PARSE_FAILURE_KEY: &str = "## GraphQLParseFailure\n";
+
+#[tokio::test]
+async fn report_the_status_code_of_the_response() {
+    let requests = || {
+        vec![
+            Request::new("query User { user(id: 1) { id } }"),
+            Request::new("query Fail { fail }"),
+            Request::new("query Broken {"),
+        ]
+    };
+
+    let (without_hook, sink) = tracing(ReportMode::TracesAndStats);
+    assert_eq!(
+        status_codes(without_hook, sink, requests()).await,
+        vec![
+            (FAIL_KEY.to_string(), 400),
+            ("# User\nquery User{user(id:0){id}}".to_string(), 200),
+            (PARSE_FAILURE_KEY.to_string(), 400),
+        ]
+    );
+
+    // The hook has the last word, then the status code set on the request.
+    let (with_hook, sink) = tracing(ReportMode::TracesAndStats);
+    let with_hook = with_hook.with_status_code(|resp| resp.is_err().then_some(200));
+    let known = ApolloTracingDataExt {
+        status_code: Some(201),
+        ..Default::default()
+    };
+    let mut requests = requests();
+    requests[0] = Request::new("query User { user(id: 1) { id } }").data(known.clone());
+    requests[1] = Request::new("query Fail { fail }").data(known);
+    assert_eq!(
+        status_codes(with_hook, sink, requests).await,
+        vec![
+            (FAIL_KEY.to_string(), 200),
+            ("# User\nquery User{user(id:0){id}}".to_string(), 201),
+            (PARSE_FAILURE_KEY.to_string(), 200),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn report_the_captured_variables() {
+    let request = || {
+        Request::new("query User($id: Int!) { user(id: $id) { id } }")
+            .variables(Variables::from_value(value!({ "id": 1 })))
+    };
+    let variables = |reports: Vec<Report>| {
+        let traces = reports[0].traces_per_query.values().next().unwrap();
+        traces.trace[0].details.variables_json.clone()
+    };
+
+    let (private, sink) = tracing(ReportMode::TracesAndStats);
+    let reports = execute_requests(private, sink, vec![request()]).await;
+    assert!(variables(reports).is_empty());
+
+    let (captured, sink) = tracing(ReportMode::TracesAndStats);
+    let captured = captured.with_variables(VariablesCapture::all());
+    let reports = execute_requests(captured, sink, vec![request()]).await;
+    assert_eq!(
+        variables(reports),
+        [("id".to_string(), "1".to_string())].into()
+    );
+}
+
+#[tokio::test]
+async fn report_the_captured_request_headers() {
+    let (tracing, sink) = tracing(ReportMode::TracesAndStats);
+    let tracing = tracing.with_headers(HeadersCapture::all());
+    let data = ApolloTracingDataExt {
+        request_headers: Some(vec![
+            ("Authorization".to_string(), "Bearer secret".to_string()),
+            ("X-Request-Id".to_string(), "1".to_string()),
+        ]),
+        ..Default::default()
+    };
+    let request = Request::new("query User { user(id: 1) { id } }").data(data);
+    let reports = execute_requests(tracing, sink, vec![request]).await;
+
+    let traces = reports[0].traces_per_query.values().next().unwrap();
+    let headers = &traces.trace[0].http.request_headers;
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers["x-request-id"].value, vec!["1"]);
+}
+
+#[tokio::test]
+async fn report_the_errors_following_the_policy() {
+    let (tracing, sink) = tracing(ReportMode::TracesAndStats);
+    let tracing = tracing.with_errors(ErrorPolicy::masked().ignore_codes(["FORBIDDEN"]));
+    let requests = vec![
+        Request::new("query Fail { fail }"),
+        Request::new("query Forbidden { forbidden }"),
+    ];
+    let reports = execute_requests(tracing, sink, requests).await;
+
+    let fail = &reports[0].traces_per_query[FAIL_KEY];
+    let errors = &fail.trace[0].root.child[0].error;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "<masked>");
+
+    let forbidden = &reports[0].traces_per_query["# Forbidden\nquery Forbidden{forbidden}"];
+    assert!(forbidden.trace[0].root.child[0].error.is_empty());
+    let stats = &forbidden.stats_with_context[0].query_latency_stats;
+    assert_eq!(stats.requests_with_errors_count, 0);
+}